@@ -31,7 +31,7 @@ export function handleBTR(protocol: BuildTimeRenderingProtocol, state: Object, s
             if (protocol.templates[stream.template].style) {
               serverHandler.write(`<style>${protocol.templates[stream.template].style}</style>`)
             }
-            serverHandler.write(`${protocol.templates[stream.template].template}</template>`)
+            serverHandler.write(`${protocol.templates[stream.template].template ?? ''}</template>`)
             const itemType = typeof item
             if (itemType === 'string' || itemType === 'number' || itemType === 'boolean' || Array.isArray(item)) {
               serverHandler.write(String(item))
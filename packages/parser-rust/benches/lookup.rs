@@ -0,0 +1,23 @@
+use btjs_parser::values::{find_value_by_dotted_path, Path};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use serde_json::{json, Value};
+
+fn large_state() -> Value {
+    let items: Vec<Value> = (0..1000)
+        .map(|i| json!({ "title": format!("Item {}", i), "tags": ["a", "b", "c"], "price": i }))
+        .collect();
+    json!({ "catalog": { "items": items } })
+}
+
+fn bench_lookup(c: &mut Criterion) {
+    let state = large_state();
+    let path = Path::parse("catalog.items").unwrap();
+
+    c.bench_function("find_value_by_dotted_path (clone)", |b| {
+        b.iter(|| find_value_by_dotted_path(black_box("catalog.items"), black_box(&state)))
+    });
+    c.bench_function("Path::lookup (borrow)", |b| b.iter(|| path.lookup(black_box(&state))));
+}
+
+criterion_group!(benches, bench_lookup);
+criterion_main!(benches);
@@ -0,0 +1,60 @@
+use btjs_parser::compiled::CompiledProtocol;
+use btjs_parser::parser::{handle_compiled_btr, ServerHandler};
+use btjs_parser::protocol::BuildTimeRenderingProtocol;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use serde_json::{json, Value};
+
+struct CountingServerHandler {
+    length: usize,
+}
+
+impl ServerHandler for CountingServerHandler {
+    fn write(&mut self, value: &str) {
+        self.length += value.len();
+    }
+
+    fn end(&mut self) {}
+}
+
+// The item template of the todo example, repeated over its `items` state.
+const ITEM_TEMPLATE: &str = include_str!("fixtures/app-item.html");
+const ITEM_STYLE: &str = include_str!("fixtures/app-item.css");
+
+fn bench_render_repeat(c: &mut Criterion) {
+    let items: Vec<Value> = (0..500).map(|i| json!({ "name": format!("Todo {}", i), "id": i })).collect();
+    let state = json!({ "items": items });
+    let protocol: BuildTimeRenderingProtocol = serde_json::from_value(json!({
+        "streams": [
+            { "type": "raw", "value": "<div class=\"items\">" },
+            { "type": "repeat", "value": "items", "template": "app-item" },
+            { "type": "raw", "value": "</div>" }
+        ],
+        "templates": {
+            "app-item": { "style": ITEM_STYLE, "template": ITEM_TEMPLATE }
+        }
+    }))
+    .unwrap();
+    let protocol = CompiledProtocol::try_from(protocol).unwrap();
+    let mut linked = protocol.clone();
+    linked.stylesheets = Some("/btjs/styles/GET".to_string());
+
+    let mut group = c.benchmark_group("render 500 todo items");
+    for (name, protocol) in [("inlined styles", &protocol), ("linked styles", &linked)] {
+        // The payload size is reported as the throughput.
+        let mut server_handler = CountingServerHandler { length: 0 };
+        handle_compiled_btr(protocol, &state, &mut server_handler);
+        group.throughput(Throughput::Bytes(server_handler.length as u64));
+
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut server_handler = CountingServerHandler { length: 0 };
+                handle_compiled_btr(protocol, black_box(&state), &mut server_handler);
+                server_handler.length
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_render_repeat);
+criterion_main!(benches);
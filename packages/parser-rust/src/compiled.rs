@@ -0,0 +1,645 @@
+use crate::expression::*;
+use crate::filters::*;
+use crate::functions::*;
+use crate::protocol::*;
+use crate::scope::*;
+use crate::values::*;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+
+// A protocol whose `when` expressions and dotted paths are parsed once when it is loaded, so rendering
+// only has to evaluate them. Register host functions and filters on `functions` and `filters` before
+// rendering.
+#[derive(Clone)]
+pub struct CompiledProtocol {
+    pub streams: Vec<CompiledStream>,
+    pub templates: HashMap<String, CompiledTemplate>,
+    pub functions: Functions,
+    pub filters: Filters,
+    // The URL template styles are served from as `{stylesheets}/{template}.css`. When set, shadow root
+    // items link the stylesheet, which the browser downloads once, instead of inlining the style.
+    pub stylesheets: Option<String>,
+}
+
+// A repeat template, `streams` is empty when the template is static.
+#[derive(Clone)]
+pub struct CompiledTemplate {
+    pub style: Option<String>,
+    pub template: String,
+    pub streams: Vec<CompiledStream>,
+    pub shadow_root_mode: BuildTimeRenderingShadowRootMode,
+    pub delegates_focus: bool,
+    // The HTML of a static light DOM template split at its slots, which are filled with each item as
+    // there is no shadow root to project it. Empty for the other templates.
+    pub parts: Vec<TemplatePart>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TemplatePart {
+    Html(String),
+    // A slot, `None` being the default slot, with the fallback rendered when the item has no value.
+    Slot(Option<String>, Vec<TemplatePart>),
+}
+
+// Nested stream lists are compiled alongside the stream, they are taken out of the protocol stream so
+// they are not kept twice: `Repeat` has its `empty` streams and `When` its `streams` and `else` streams.
+#[derive(Clone)]
+pub enum CompiledStream {
+    Attribute(BuildTimeRenderingStreamAttribute, Binding),
+    Raw(BuildTimeRenderingStreamRaw),
+    Repeat(BuildTimeRenderingStreamRepeat, Path, Vec<CompiledStream>),
+    Signal(BuildTimeRenderingStreamSignal, Binding),
+    When(BuildTimeRenderingStreamWhen, Expr, Vec<CompiledStream>, Vec<CompiledStream>),
+}
+
+// What a signal or attribute renders, either a dotted path or an expression when the stream is marked
+// as one.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Binding {
+    Path(Path),
+    Expression(Expr),
+}
+
+impl Binding {
+    // Resolves the bound value, `None` when it is missing or null so both bindings fall back to the default
+    // value alike.
+    pub fn resolve<'a>(&'a self, scope: Scope<'_, 'a>, functions: &Functions) -> Option<Cow<'a, Value>> {
+        let value = match self {
+            Binding::Path(path) => scope.lookup(path),
+            Binding::Expression(expr) => Some(evaluate_in(expr, scope, functions)),
+        };
+        value.filter(|value| !value.is_null())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompileErrorKind {
+    Expression(ParseError),
+    Path(PathError),
+    // A structural when without a matching `whenEnd`.
+    UnclosedWhen,
+    // A `whenEnd` without a structural when to close.
+    UnexpectedWhenEnd,
+    // A template with both `template` HTML and `streams`, the HTML is never rendered.
+    TemplateWithStreams,
+}
+
+// A stream that cannot be compiled, `stream_index` is the position of the stream, or of the stream
+// containing it, in the protocol streams or in the streams of `template`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompileError {
+    pub template: Option<String>,
+    pub stream_index: usize,
+    pub value: String,
+    pub kind: CompileErrorKind,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let location = match &self.template {
+            Some(template) => format!("stream {} of template '{}'", self.stream_index, template),
+            None => format!("stream {}", self.stream_index),
+        };
+        match &self.kind {
+            CompileErrorKind::Expression(error) => {
+                write!(f, "Invalid expression '{}' in {}: {}", self.value, location, error)
+            }
+            CompileErrorKind::Path(error) => write!(f, "Invalid path '{}' in {}: {}", self.value, location, error),
+            CompileErrorKind::UnclosedWhen => write!(f, "Structural when '{}' in {} is never closed", self.value, location),
+            CompileErrorKind::UnexpectedWhenEnd => write!(f, "Unexpected whenEnd in {}", location),
+            CompileErrorKind::TemplateWithStreams => write!(
+                f,
+                "Template '{}' has both template HTML and streams, the HTML is never rendered",
+                self.template.as_deref().unwrap_or_default()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+impl TryFrom<BuildTimeRenderingProtocol> for CompiledProtocol {
+    type Error = CompileError;
+
+    fn try_from(protocol: BuildTimeRenderingProtocol) -> Result<Self, Self::Error> {
+        compile(protocol, &Errors::Strict)
+    }
+}
+
+impl CompiledProtocol {
+    // Compiles without failing, as the protocol was rendered before it could be compiled: invalid `when`
+    // expressions are always false and invalid paths are looked up segment by segment.
+    pub fn compile_lenient(protocol: BuildTimeRenderingProtocol) -> CompiledProtocol {
+        match compile(protocol, &Errors::Lenient) {
+            Ok(compiled) => compiled,
+            Err(_) => unreachable!("lenient compilation never fails"),
+        }
+    }
+
+    // The style of a template, served at its `stylesheet_href` when the protocol links styles.
+    pub fn stylesheet(&self, template: &str) -> Option<&str> {
+        self.templates.get(template)?.style.as_deref()
+    }
+
+    // The URL items of a template link its style from, `{stylesheets}/{template}.css`.
+    pub fn stylesheet_href(&self, template: &str) -> Option<String> {
+        let stylesheets = self.stylesheets.as_ref()?;
+        Some(format!("{}/{}.css", stylesheets.trim_end_matches('/'), template))
+    }
+}
+
+// Every error compiling a protocol, which compiles leniently past them.
+pub(crate) fn compile_errors(protocol: &BuildTimeRenderingProtocol) -> Vec<CompileError> {
+    let errors = RefCell::new(Vec::new());
+    let _ = compile(protocol.clone(), &Errors::Collect(&errors));
+    errors.into_inner()
+}
+
+// What compiling does with an invalid stream: fail on it, compile it leniently, or compile it leniently
+// and keep the error.
+enum Errors<'a> {
+    Strict,
+    Lenient,
+    Collect(&'a RefCell<Vec<CompileError>>),
+}
+
+impl Errors<'_> {
+    // Fails on the error when strict, or lets compilation continue with the lenient fallback.
+    fn report(&self, error: CompileError) -> Result<(), CompileError> {
+        match self {
+            Errors::Strict => Err(error),
+            Errors::Lenient => Ok(()),
+            Errors::Collect(errors) => {
+                errors.borrow_mut().push(error);
+                Ok(())
+            }
+        }
+    }
+}
+
+fn compile(protocol: BuildTimeRenderingProtocol, errors: &Errors) -> Result<CompiledProtocol, CompileError> {
+    let mut templates = HashMap::with_capacity(protocol.templates.len());
+    for (name, template) in protocol.templates {
+        if has_unused_html(&template) {
+            errors.report(CompileError {
+                template: Some(name.clone()),
+                stream_index: 0,
+                value: template.template.clone(),
+                kind: CompileErrorKind::TemplateWithStreams,
+            })?;
+        }
+        let streams = compile_streams(template.streams, errors, Some(&name), None)?;
+        let parts = if streams.is_empty() && template.shadow_root_mode == BuildTimeRenderingShadowRootMode::None {
+            parse_slots(&template.template)
+        } else {
+            Vec::new()
+        };
+        let compiled = CompiledTemplate {
+            style: template.style,
+            template: template.template,
+            streams,
+            shadow_root_mode: template.shadow_root_mode,
+            delegates_focus: template.delegates_focus,
+            parts,
+        };
+        templates.insert(name, compiled);
+    }
+
+    Ok(CompiledProtocol {
+        streams: compile_streams(protocol.streams, errors, None, None)?,
+        templates,
+        functions: Functions::new(),
+        filters: Filters::new(),
+        stylesheets: None,
+    })
+}
+
+// Templates rendered from streams ignore their HTML, so setting both loses markup.
+fn has_unused_html(template: &BuildTimeRenderingTemplate) -> bool {
+    !template.streams.is_empty() && !template.template.trim().is_empty()
+}
+
+// Splits template HTML at its slots. Tags are read past their quoted attribute values and slots are
+// matched by depth, so a slot in the fallback of another slot stays within it. Unclosed slots end with
+// the template, like the browser closes them.
+fn parse_slots(html: &str) -> Vec<TemplatePart> {
+    // The open slots from the outermost, the template itself being the first.
+    let mut open: Vec<(Option<String>, Vec<TemplatePart>)> = vec![(None, Vec::new())];
+    let mut text_start = 0;
+    let mut position = 0;
+
+    while let Some(offset) = html[position..].find('<') {
+        let start = position + offset;
+        if html[start..].starts_with("<!--") {
+            position = html[start..].find("-->").map_or(html.len(), |end| start + end + 3);
+            continue;
+        }
+        let is_end = html[start + 1..].starts_with('/');
+        let name_start = if is_end { start + 2 } else { start + 1 };
+        if !html[name_start..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+            position = start + 1;
+            continue;
+        }
+
+        let end = tag_end(html, name_start);
+        let tag = &html[name_start..end];
+        let name_end = tag.find(|c: char| c.is_ascii_whitespace() || c == '/' || c == '>').unwrap_or(tag.len());
+        if tag[..name_end].eq_ignore_ascii_case("slot") && (!is_end || open.len() > 1) {
+            push_html(&mut open, &html[text_start..start]);
+            if is_end {
+                let (name, fallback) = open.pop().unwrap_or_default();
+                open.last_mut().unwrap().1.push(TemplatePart::Slot(name, fallback));
+            } else {
+                let name = attribute(&tag[name_end..], "name").filter(|name| !name.is_empty());
+                open.push((name.map(str::to_string), Vec::new()));
+            }
+            text_start = end;
+        }
+        position = end;
+    }
+
+    push_html(&mut open, &html[text_start..]);
+    while open.len() > 1 {
+        let (name, fallback) = open.pop().unwrap_or_default();
+        open.last_mut().unwrap().1.push(TemplatePart::Slot(name, fallback));
+    }
+    open.pop().map(|(_, parts)| parts).unwrap_or_default()
+}
+
+// Adds the HTML before a slot tag to the innermost open slot.
+fn push_html(open: &mut [(Option<String>, Vec<TemplatePart>)], html: &str) {
+    if let Some((_, parts)) = open.last_mut().filter(|_| !html.is_empty()) {
+        parts.push(TemplatePart::Html(html.to_string()));
+    }
+}
+
+// The end of the tag whose name starts at `start`, after its `>` outside of quoted attribute values.
+fn tag_end(html: &str, start: usize) -> usize {
+    let mut quote = None;
+    for (index, c) in html[start..].char_indices() {
+        match (quote, c) {
+            (Some(q), _) if q == c => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '>') => return start + index + 1,
+            _ => {}
+        }
+    }
+    html.len()
+}
+
+// The value of an attribute in the attributes of a tag, up to its `>`.
+fn attribute<'a>(attributes: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = attributes.trim_end_matches('>');
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == '/');
+        if rest.is_empty() {
+            return None;
+        }
+        let name_end = rest.find(|c: char| c.is_ascii_whitespace() || c == '=' || c == '/').unwrap_or(rest.len());
+        let attribute_name = &rest[..name_end];
+        rest = rest[name_end..].trim_start();
+
+        let mut value = "";
+        if let Some(after) = rest.strip_prefix('=') {
+            let after = after.trim_start();
+            let rest_start = match after.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let end = after[1..].find(quote).map_or(after.len(), |end| end + 1);
+                    value = &after[1..end];
+                    (end + 1).min(after.len())
+                }
+                _ => {
+                    let end = after.find(|c: char| c.is_ascii_whitespace()).unwrap_or(after.len());
+                    value = &after[..end];
+                    end
+                }
+            };
+            rest = &after[rest_start..];
+        }
+        if attribute_name.eq_ignore_ascii_case(name) {
+            return Some(value);
+        }
+    }
+}
+
+// Compiles a list of streams, the streams of a block report errors at the index of the stream
+// `containing` them.
+fn compile_streams(
+    protocol_streams: Vec<BuildTimeRenderingStream>,
+    errors: &Errors,
+    template: Option<&str>,
+    containing: Option<usize>,
+) -> Result<Vec<CompiledStream>, CompileError> {
+    let mut protocol_streams = protocol_streams
+        .into_iter()
+        .enumerate()
+        .map(|(index, stream)| (containing.unwrap_or(index), stream));
+    compile_region(&mut protocol_streams, errors, template, None)
+}
+
+// Compiles streams until the end of the list, or until the `whenEnd` closing the structural when `opened`
+// by the caller. The streams in between become the `streams` of that when, so it renders like a when
+// with a block. Leniently, unmatched ends are ignored and unclosed whens end with the list.
+fn compile_region(
+    protocol_streams: &mut impl Iterator<Item = (usize, BuildTimeRenderingStream)>,
+    errors: &Errors,
+    template: Option<&str>,
+    opened: Option<(usize, &str)>,
+) -> Result<Vec<CompiledStream>, CompileError> {
+    let error_at = |stream_index: usize, value: &str, kind: CompileErrorKind| CompileError {
+        template: template.map(str::to_string),
+        stream_index,
+        value: value.to_string(),
+        kind,
+    };
+    let mut streams = Vec::new();
+
+    while let Some((stream_index, stream)) = protocol_streams.next() {
+        let error = |value: &str, kind: CompileErrorKind| error_at(stream_index, value, kind);
+        let compile_path = |value: &str| match Path::parse(value) {
+            Ok(path) => Ok(path),
+            Err(path_error) => {
+                errors.report(error(value, CompileErrorKind::Path(path_error)))?;
+                Ok(Path::lenient(value))
+            }
+        };
+        let compile_expression = |value: &str, fallback: Expr| match parse(value) {
+            Ok(expr) => Ok(expr),
+            Err(parse_error) => {
+                errors.report(error(value, CompileErrorKind::Expression(parse_error)))?;
+                Ok(fallback)
+            }
+        };
+        let compile_block =
+            |block: Vec<BuildTimeRenderingStream>| compile_streams(block, errors, template, Some(stream_index));
+        let compile_binding = |value: &str, expression: bool| {
+            if expression {
+                compile_expression(value, Expr::Literal(Value::Null)).map(Binding::Expression)
+            } else {
+                compile_path(value).map(Binding::Path)
+            }
+        };
+
+        let compiled = match stream {
+            BuildTimeRenderingStream::Attribute(attribute_stream) => {
+                let binding = compile_binding(&attribute_stream.value, attribute_stream.expression)?;
+                CompiledStream::Attribute(attribute_stream, binding)
+            }
+            BuildTimeRenderingStream::Raw(raw_stream) => CompiledStream::Raw(raw_stream),
+            BuildTimeRenderingStream::Repeat(mut repeat_stream) => {
+                let path = compile_path(&repeat_stream.value)?;
+                let empty = compile_block(std::mem::take(&mut repeat_stream.empty))?;
+                CompiledStream::Repeat(repeat_stream, path, empty)
+            }
+            BuildTimeRenderingStream::Signal(signal_stream) => {
+                let binding = compile_binding(&signal_stream.value, signal_stream.expression)?;
+                CompiledStream::Signal(signal_stream, binding)
+            }
+            BuildTimeRenderingStream::When(mut when_stream) => {
+                let expr = compile_expression(&when_stream.value, Expr::Literal(Value::Bool(false)))?;
+                let mut streams = compile_block(std::mem::take(&mut when_stream.streams))?;
+                if when_stream.mode == BuildTimeRenderingWhenMode::Structural {
+                    let opened = Some((stream_index, when_stream.value.as_str()));
+                    streams.extend(compile_region(protocol_streams, errors, template, opened)?);
+                }
+                let else_streams = compile_block(std::mem::take(&mut when_stream.else_streams))?;
+                CompiledStream::When(when_stream, expr, streams, else_streams)
+            }
+            BuildTimeRenderingStream::WhenEnd if opened.is_some() => return Ok(streams),
+            BuildTimeRenderingStream::WhenEnd => {
+                errors.report(error("", CompileErrorKind::UnexpectedWhenEnd))?;
+                continue;
+            }
+        };
+        streams.push(compiled);
+    }
+
+    if let Some((stream_index, value)) = opened {
+        errors.report(error_at(stream_index, value, CompileErrorKind::UnclosedWhen))?;
+    }
+    Ok(streams)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn protocol(streams: Vec<BuildTimeRenderingStream>) -> BuildTimeRenderingProtocol {
+        BuildTimeRenderingProtocol {
+            streams,
+            templates: HashMap::new(),
+        }
+    }
+
+    fn when(value: &str) -> BuildTimeRenderingStream {
+        BuildTimeRenderingStream::When(BuildTimeRenderingStreamWhen {
+            value: value.to_string(),
+            mode: BuildTimeRenderingWhenMode::Style,
+            style: None,
+            streams: Vec::new(),
+            else_streams: Vec::new(),
+        })
+    }
+
+    fn signal(value: &str) -> BuildTimeRenderingStream {
+        BuildTimeRenderingStream::Signal(BuildTimeRenderingStreamSignal {
+            value: value.to_string(),
+            default_value: None,
+            html: false,
+            expression: false,
+            filters: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn test_compile() {
+        let compiled = CompiledProtocol::try_from(protocol(vec![signal("name.first"), when("a > 1 && b")])).unwrap();
+        assert!(matches!(&compiled.streams[0], CompiledStream::Signal(_, Binding::Path(path)) if path.to_string() == "name.first"));
+        assert!(matches!(&compiled.streams[1], CompiledStream::When(_, Expr::Binary(BinaryOperator::And, _, _), ..)));
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let error = CompiledProtocol::try_from(protocol(vec![signal("a"), when("a >")]))
+            .err()
+            .unwrap();
+        assert_eq!(error.stream_index, 1);
+        assert_eq!(
+            error.to_string(),
+            "Invalid expression 'a >' in stream 1: Unexpected end of expression at position 3"
+        );
+
+        let error = CompiledProtocol::try_from(protocol(vec![signal("a..b")]))
+            .err()
+            .unwrap();
+        assert_eq!(error.stream_index, 0);
+        assert!(matches!(error.kind, CompileErrorKind::Path(_)));
+    }
+
+    #[test]
+    fn test_compile_template_errors() {
+        let mut protocol = protocol(vec![]);
+        protocol.templates.insert(
+            "todo-item".to_string(),
+            BuildTimeRenderingTemplate {
+                style: None,
+                template: String::new(),
+                streams: vec![signal("title"), when("done &&")],
+                shadow_root_mode: BuildTimeRenderingShadowRootMode::Open,
+                delegates_focus: false,
+            },
+        );
+        let error = CompiledProtocol::try_from(protocol.clone()).err().unwrap();
+        assert_eq!(error.template.as_deref(), Some("todo-item"));
+        assert_eq!(
+            error.to_string(),
+            "Invalid expression 'done &&' in stream 1 of template 'todo-item': Unexpected end of expression at position 7"
+        );
+
+        let compiled = CompiledProtocol::compile_lenient(protocol);
+        assert_eq!(compiled.templates["todo-item"].streams.len(), 2);
+    }
+
+    #[test]
+    fn test_compile_template_with_html_and_streams() {
+        let mut protocol = protocol(vec![]);
+        protocol.templates.insert(
+            "todo-item".to_string(),
+            BuildTimeRenderingTemplate {
+                style: None,
+                template: "<li></li>".to_string(),
+                streams: vec![signal("title")],
+                shadow_root_mode: BuildTimeRenderingShadowRootMode::Open,
+                delegates_focus: false,
+            },
+        );
+        let error = CompiledProtocol::try_from(protocol.clone()).err().unwrap();
+        assert_eq!(error.kind, CompileErrorKind::TemplateWithStreams);
+        assert_eq!(
+            error.to_string(),
+            "Template 'todo-item' has both template HTML and streams, the HTML is never rendered"
+        );
+        assert_eq!(CompiledProtocol::compile_lenient(protocol).templates["todo-item"].streams.len(), 1);
+    }
+
+    #[test]
+    fn test_compile_nested_errors() {
+        let when_stream = BuildTimeRenderingStreamWhen {
+            value: "a".to_string(),
+            mode: BuildTimeRenderingWhenMode::Style,
+            style: None,
+            streams: vec![signal("a")],
+            else_streams: vec![signal("b"), when("b ==")],
+        };
+        let error = CompiledProtocol::try_from(protocol(vec![signal("a"), BuildTimeRenderingStream::When(when_stream)]))
+            .err()
+            .unwrap();
+        assert_eq!(error.stream_index, 1);
+        assert_eq!(error.value, "b ==");
+
+        let compiled = CompiledProtocol::compile_lenient(protocol(vec![BuildTimeRenderingStream::When(
+            BuildTimeRenderingStreamWhen {
+                value: "a".to_string(),
+                mode: BuildTimeRenderingWhenMode::Style,
+                style: None,
+                streams: vec![signal("a")],
+                else_streams: Vec::new(),
+            },
+        )]));
+        assert!(matches!(&compiled.streams[0], CompiledStream::When(when_stream, _, streams, else_streams)
+            if when_stream.streams.is_empty() && streams.len() == 1 && else_streams.is_empty()));
+    }
+
+    #[test]
+    fn test_compile_structural_when() {
+        let structural = |value: &str| {
+            BuildTimeRenderingStream::When(BuildTimeRenderingStreamWhen {
+                value: value.to_string(),
+                mode: BuildTimeRenderingWhenMode::Structural,
+                style: None,
+                streams: Vec::new(),
+                else_streams: Vec::new(),
+            })
+        };
+        let end = || BuildTimeRenderingStream::WhenEnd;
+
+        let compiled =
+            CompiledProtocol::try_from(protocol(vec![structural("a"), signal("a"), structural("b"), end(), end(), signal("c")])).unwrap();
+        assert_eq!(compiled.streams.len(), 2);
+        let CompiledStream::When(_, _, streams, _) = &compiled.streams[0] else {
+            panic!("Expected a when stream");
+        };
+        assert!(matches!(&streams[..], [CompiledStream::Signal(..), CompiledStream::When(_, _, nested, _)] if nested.is_empty()));
+
+        let error = CompiledProtocol::try_from(protocol(vec![signal("a"), structural("a"), structural("b"), end()]))
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "Structural when 'a' in stream 1 is never closed");
+
+        let error = CompiledProtocol::try_from(protocol(vec![structural("a"), end(), end()])).err().unwrap();
+        assert_eq!(error.to_string(), "Unexpected whenEnd in stream 2");
+
+        let compiled = CompiledProtocol::compile_lenient(protocol(vec![end(), structural("a"), signal("a")]));
+        assert!(matches!(&compiled.streams[..], [CompiledStream::When(_, _, streams, _)] if streams.len() == 1));
+    }
+
+    #[test]
+    fn test_compile_expression_binding() {
+        let mut stream = BuildTimeRenderingStreamSignal {
+            value: "a +".to_string(),
+            default_value: None,
+            html: false,
+            expression: true,
+            filters: Vec::new(),
+        };
+        let error = CompiledProtocol::try_from(protocol(vec![BuildTimeRenderingStream::Signal(stream.clone())]))
+            .err()
+            .unwrap();
+        assert!(matches!(error.kind, CompileErrorKind::Expression(_)));
+
+        stream.value = "a + 1".to_string();
+        let compiled = CompiledProtocol::try_from(protocol(vec![BuildTimeRenderingStream::Signal(stream)])).unwrap();
+        let CompiledStream::Signal(_, binding) = &compiled.streams[0] else {
+            panic!("Expected a signal stream");
+        };
+        assert_eq!(
+            binding.resolve(Scope::new(&[ScopeFrame::Root(&serde_json::json!({ "a": 1 }))]), &compiled.functions).as_deref(),
+            Some(&serde_json::json!(2))
+        );
+    }
+
+    #[test]
+    fn test_compile_lenient() {
+        let compiled = CompiledProtocol::compile_lenient(protocol(vec![signal("a..b"), when("a >")]));
+        let keys = ["a", "", "b"].map(|key| PathSegment::Key(key.to_string()));
+        assert!(matches!(&compiled.streams[0], CompiledStream::Signal(_, Binding::Path(path)) if path.segments() == keys));
+        assert!(matches!(&compiled.streams[1], CompiledStream::When(_, Expr::Literal(Value::Bool(false)), ..)));
+    }
+
+    #[test]
+    fn test_parse_slots() {
+        let html = |html: &str| TemplatePart::Html(html.to_string());
+        let slot = |name: Option<&str>, fallback| TemplatePart::Slot(name.map(str::to_string), fallback);
+
+        let parts = parse_slots("<p a='>'><slot name=\"a\"><SLOT name=b>x</SLOT></slot><!-- <slot> --></p></slot>");
+        assert_eq!(
+            parts,
+            vec![
+                html("<p a='>'>"),
+                slot(Some("a"), vec![slot(Some("b"), vec![html("x")])]),
+                html("<!-- <slot> --></p></slot>"),
+            ]
+        );
+
+        let parts = parse_slots("<slot class=\"name=c\" name=\"\"></slot>a < b<slotted><slot name='d'>e");
+        assert_eq!(
+            parts,
+            vec![slot(None, Vec::new()), html("a < b<slotted>"), slot(Some("d"), vec![html("e")])]
+        );
+    }
+}
@@ -0,0 +1,64 @@
+use std::borrow::Cow;
+
+// Escapes a value that is written as the text content of an element.
+pub fn escape_text(value: &str) -> Cow<'_, str> {
+    escape(value, |c| match c {
+        '&' => Some("&amp;"),
+        '<' => Some("&lt;"),
+        '>' => Some("&gt;"),
+        _ => None,
+    })
+}
+
+// Escapes a value that is written within a double or single quoted attribute value.
+pub fn escape_attribute(value: &str) -> Cow<'_, str> {
+    escape(value, |c| match c {
+        '&' => Some("&amp;"),
+        '<' => Some("&lt;"),
+        '>' => Some("&gt;"),
+        '"' => Some("&quot;"),
+        '\'' => Some("&#39;"),
+        _ => None,
+    })
+}
+
+// Replaces every character that has an entity, only allocating when something needs escaping.
+fn escape(value: &str, entity: impl Fn(char) -> Option<&'static str>) -> Cow<'_, str> {
+    let Some(first) = value.find(|c| entity(c).is_some()) else {
+        return Cow::Borrowed(value);
+    };
+
+    let mut escaped = String::with_capacity(value.len() + 8);
+    escaped.push_str(&value[..first]);
+    for c in value[first..].chars() {
+        match entity(c) {
+            Some(replacement) => escaped.push_str(replacement),
+            None => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_text() {
+        assert_eq!(escape_text("plain text"), "plain text");
+        assert!(matches!(escape_text("plain text"), Cow::Borrowed(_)));
+        assert_eq!(
+            escape_text("<script>alert(\"x\")</script> & 'y'"),
+            "&lt;script&gt;alert(\"x\")&lt;/script&gt; &amp; 'y'"
+        );
+    }
+
+    #[test]
+    fn test_escape_attribute() {
+        assert_eq!(escape_attribute("red blue"), "red blue");
+        assert_eq!(
+            escape_attribute("\" onload=\"alert('x')\" <b> &"),
+            "&quot; onload=&quot;alert(&#39;x&#39;)&quot; &lt;b&gt; &amp;"
+        );
+    }
+}
@@ -1,184 +1,511 @@
+use crate::functions::*;
+use crate::scope::*;
 use crate::values::*;
-use lazy_static::lazy_static;
-use regex::Regex;
-use serde_json::Number;
 use serde_json::Value;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::Range;
 
-const OPERATORS: [&str; 10] = ["&&", "||", "==", ">=", "<=", ">", "<", "!", "(", ")"];
+const OPERATORS: [&str; 20] = [
+    "&&", "||", "??", "==", "!=", ">=", "<=", ">", "<", "!", "+", "-", "*", "/", "%", "?", ":", "(", ")", ",",
+];
 
-lazy_static! {
-    static ref RE: Regex = {
-        let mut patterns: Vec<_> = OPERATORS
-            .iter()
-            .map(|&separator| regex::escape(separator))
-            .collect();
-        patterns.sort_by(|a, b| b.len().cmp(&a.len()));
-        let pattern = patterns.join("|");
-        Regex::new(&pattern).unwrap()
-    };
+// A token of an expression. Literals are numbers, strings, `true`, `false` and `null`, identifiers are
+// dotted paths to properties in the state object.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    Literal(Value),
+    Identifier(String),
+    Operator(&'static str),
 }
 
-// Parses an expression into a vector of strings. The strings are either operators or operands.
-pub fn parse_expression(expression: &str) -> Vec<String> {
-    let mut result = Vec::new();
-    let mut last = 0;
-    for mat in RE.find_iter(expression) {
-        if last != mat.start() {
-            result.push(expression[last..mat.start()].trim().to_string());
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnaryOperator {
+    Not,
+    Negate,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinaryOperator {
+    Coalesce,
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+impl BinaryOperator {
+    fn from_token(token: &str) -> Option<BinaryOperator> {
+        match token {
+            "??" => Some(BinaryOperator::Coalesce),
+            "||" => Some(BinaryOperator::Or),
+            "&&" => Some(BinaryOperator::And),
+            "==" => Some(BinaryOperator::Equal),
+            "!=" => Some(BinaryOperator::NotEqual),
+            "<" => Some(BinaryOperator::Less),
+            "<=" => Some(BinaryOperator::LessEqual),
+            ">" => Some(BinaryOperator::Greater),
+            ">=" => Some(BinaryOperator::GreaterEqual),
+            "+" => Some(BinaryOperator::Add),
+            "-" => Some(BinaryOperator::Subtract),
+            "*" => Some(BinaryOperator::Multiply),
+            "/" => Some(BinaryOperator::Divide),
+            "%" => Some(BinaryOperator::Remainder),
+            _ => None,
         }
-        result.push(mat.as_str().to_string());
-        last = mat.end();
     }
-    if last < expression.len() {
-        result.push(expression[last..].trim().to_string());
+
+    // Higher binds tighter, all binary operators are left associative. The conditional operator binds
+    // looser than all of them.
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOperator::Coalesce => 1,
+            BinaryOperator::Or => 2,
+            BinaryOperator::And => 3,
+            BinaryOperator::Equal | BinaryOperator::NotEqual => 4,
+            BinaryOperator::Less
+            | BinaryOperator::LessEqual
+            | BinaryOperator::Greater
+            | BinaryOperator::GreaterEqual => 5,
+            BinaryOperator::Add | BinaryOperator::Subtract => 6,
+            BinaryOperator::Multiply | BinaryOperator::Divide | BinaryOperator::Remainder => 7,
+        }
     }
-    result
 }
 
-// Safely evaluates an expression using a state object. The expression is a string that can contain
-// logical operators and dotted paths to properties in the state object.
-pub fn safe_evaluate_expression(expression: &str, state: &Value) -> bool {
-    let tokens: Vec<String> = parse_expression(expression);
-    let mut tokens_ref: Vec<&str> = tokens.iter().map(AsRef::as_ref).collect();
-    tokens_ref.reverse();
-    match evaluate(&mut tokens_ref, state) {
-        Value::Bool(b) => b,
-        _ => false,
+// The syntax tree of a parsed expression.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Literal(Value),
+    Path(Path),
+    Unary(UnaryOperator, Box<Expr>),
+    Binary(BinaryOperator, Box<Expr>, Box<Expr>),
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+// A syntax error in an expression, `position` is the byte offset where the error was found.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    pub position: usize,
+}
+
+impl ParseError {
+    fn new(message: impl Into<String>, position: usize) -> Self {
+        ParseError {
+            message: message.into(),
+            position,
+        }
     }
 }
 
-// Evaluates a vector of tokens into a Value. The tokens are either operators or operands.
-// It uses recursion to evaluate nested expressions within parentheses.
-fn evaluate(tokens: &mut Vec<&str>, state: &Value) -> Value {
-    let mut current_value = None;
-    let mut current_operator = None;
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
 
-    while let Some(token) = tokens.pop() {
-        match token {
-            "(" => {
-                let value = evaluate(tokens, state);
-                current_value = Some(apply_operator(current_value, current_operator, value));
-                current_operator = None;
+impl std::error::Error for ParseError {}
+
+// Splits an expression into tokens along with the byte range each token covers.
+pub fn tokenize(expression: &str) -> Result<Vec<(Token, Range<usize>)>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        if c == '"' || c == '\'' {
+            chars.next();
+            let mut value = String::new();
+            let mut closed = false;
+            while let Some((_, next)) = chars.next() {
+                match next {
+                    '\\' => match chars.next() {
+                        Some((_, escaped)) => value.push(escaped),
+                        None => break,
+                    },
+                    _ if next == c => {
+                        closed = true;
+                        break;
+                    }
+                    _ => value.push(next),
+                }
             }
-            ")" => break,
-            "==" | "!=" | "<" | "<=" | ">" | ">=" | "&&" | "||" | "!" => {
-                if current_operator.is_some() {
-                    let value = evaluate(tokens, state);
-                    current_value = Some(apply_operator(current_value, current_operator, value));
+            if !closed {
+                return Err(ParseError::new("Unterminated string", start));
+            }
+            let end = chars.peek().map_or(expression.len(), |&(i, _)| i);
+            tokens.push((Token::Literal(Value::String(value)), start..end));
+            continue;
+        }
+
+        if c.is_ascii_digit() || is_identifier_char(c) {
+            let mut end = start;
+            while let Some(&(i, next)) = chars.peek() {
+                if next == '[' && !c.is_ascii_digit() {
+                    // Bracket access is part of the path, e.g. `items[0]` or `map["a b"]`.
+                    end = skip_bracket(expression, i, &mut chars)?;
+                    continue;
+                }
+                if !is_identifier_char(next) {
+                    break;
                 }
-                current_operator = Some(token);
+                end = i + next.len_utf8();
+                chars.next();
             }
-            _ => {
-                let value = parse_value(token, state);
-                current_value = Some(apply_operator(current_value, current_operator, value));
-                current_operator = None;
+            let word = &expression[start..end];
+            let token = if c.is_ascii_digit() {
+                let number = word
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|number| number.is_finite())
+                    .ok_or_else(|| ParseError::new(format!("Invalid number '{}'", word), start))?;
+                Token::Literal(number_to_value(number))
+            } else {
+                match word {
+                    "true" => Token::Literal(Value::Bool(true)),
+                    "false" => Token::Literal(Value::Bool(false)),
+                    "null" => Token::Literal(Value::Null),
+                    _ => Token::Identifier(word.to_string()),
+                }
+            };
+            tokens.push((token, start..end));
+            continue;
+        }
+
+        let rest = &expression[start..];
+        let operator = OPERATORS
+            .iter()
+            .filter(|operator| rest.starts_with(**operator))
+            .max_by_key(|operator| operator.len())
+            .ok_or_else(|| ParseError::new(format!("Unexpected character '{}'", c), start))?;
+        for _ in 0..operator.chars().count() {
+            chars.next();
+        }
+        tokens.push((Token::Operator(operator), start..start + operator.len()));
+    }
+
+    Ok(tokens)
+}
+
+// Skips over `[...]` starting at `open`, ignoring brackets within quoted keys. Returns the end of it.
+fn skip_bracket(
+    expression: &str,
+    open: usize,
+    chars: &mut std::iter::Peekable<std::str::CharIndices>,
+) -> Result<usize, ParseError> {
+    let mut quote = None;
+    chars.next();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (Some(_), '\\') => {
+                chars.next();
             }
+            (Some(q), _) if q == c => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, ']') => return Ok(i + 1),
+            (None, _) => {}
         }
     }
+    Err(ParseError::new(
+        format!("Unterminated '[' in '{}'", &expression[open..]),
+        open,
+    ))
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$' || c == '.'
+}
 
-    if current_operator.is_some() {
-        let value = evaluate(tokens, state);
-        current_value = Some(apply_operator(current_value, current_operator, value));
+// Parses an expression into a vector of strings. The strings are either operators or operands.
+// An expression that cannot be tokenized is returned as a single string.
+pub fn parse_expression(expression: &str) -> Vec<String> {
+    match tokenize(expression) {
+        Ok(tokens) => tokens
+            .into_iter()
+            .map(|(_, range)| expression[range].to_string())
+            .collect(),
+        Err(_) => vec![expression.trim().to_string()],
     }
+}
 
-    let current_value = current_value.unwrap_or(Value::Null);
-    return current_value;
+// Parses an expression into its syntax tree.
+pub fn parse(expression: &str) -> Result<Expr, ParseError> {
+    let tokens = tokenize(expression)?;
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+        end: expression.len(),
+    };
+    let expr = parser.parse_conditional()?;
+    match parser.tokens.get(parser.position) {
+        Some((_, range)) => Err(ParseError::new(
+            format!("Unexpected '{}'", &expression[range.clone()]),
+            range.start,
+        )),
+        None => Ok(expr),
+    }
 }
 
-fn value_to_bool(value: Option<Value>) -> bool {
-    value.map_or(false, |v| match v {
-        Value::Bool(b) => b,
-        Value::Number(n) => n.as_f64() != Some(0.0),
-        Value::String(s) => !s.is_empty(),
-        Value::Array(a) => !a.is_empty(),
-        Value::Object(o) => !o.is_empty(),
-        _ => false,
-    })
+// A precedence climbing parser over the tokens of an expression.
+struct Parser {
+    tokens: Vec<(Token, Range<usize>)>,
+    position: usize,
+    end: usize,
 }
 
-// Applies an operator to two values. The operator can be a logical or comparison operator.
-// The values can be booleans, numbers, strings, arrays, or objects.
-fn apply_operator(value1: Option<Value>, operator: Option<&str>, value2: Value) -> Value {
-    match operator {
-        Some("==") => match (value1, value2) {
-            (Some(Value::Bool(b1)), Value::Bool(b2)) => Value::Bool(b1 == b2),
-            (Some(Value::Number(n1)), Value::Number(n2)) => Value::Bool(n1.as_f64() == n2.as_f64()),
-            (Some(Value::String(s1)), Value::String(s2)) => Value::Bool(s1 == s2),
-            (Some(Value::Array(a1)), Value::Array(a2)) => Value::Bool(a1 == a2),
-            (Some(Value::Object(o1)), Value::Object(o2)) => Value::Bool(o1 == o2),
-            _ => Value::Bool(false),
-        },
-        Some("!=") => Value::Bool(value1 != Some(value2)),
-        Some(">") => {
-            if let (Some(Value::Number(n1)), Value::Number(n2)) = (value1, value2) {
-                Value::Bool(n1.as_f64() > n2.as_f64())
-            } else {
-                Value::Bool(false)
-            }
+impl Parser {
+    fn peek_operator(&self) -> Option<&'static str> {
+        match self.tokens.get(self.position) {
+            Some((Token::Operator(operator), _)) => Some(operator),
+            _ => None,
         }
-        Some("<") => {
-            if let (Some(Value::Number(n1)), Value::Number(n2)) = (value1, value2) {
-                Value::Bool(n1.as_f64() < n2.as_f64())
-            } else {
-                Value::Bool(false)
-            }
+    }
+
+    fn current_position(&self) -> usize {
+        self.tokens.get(self.position).map_or(self.end, |(_, range)| range.start)
+    }
+
+    fn expect_operator(&mut self, operator: &'static str) -> Result<(), ParseError> {
+        if self.peek_operator() != Some(operator) {
+            return Err(ParseError::new(format!("Expected '{}'", operator), self.current_position()));
         }
-        Some(">=") => {
-            if let (Some(Value::Number(n1)), Value::Number(n2)) = (value1, value2) {
-                Value::Bool(n1.as_f64() >= n2.as_f64())
-            } else {
-                Value::Bool(false)
+        self.position += 1;
+        Ok(())
+    }
+
+    // `condition ? then : otherwise`, right associative so conditionals can be chained.
+    fn parse_conditional(&mut self) -> Result<Expr, ParseError> {
+        let condition = self.parse_binary(0)?;
+        if self.peek_operator() != Some("?") {
+            return Ok(condition);
+        }
+        self.position += 1;
+        let then = self.parse_conditional()?;
+        self.expect_operator(":")?;
+        let otherwise = self.parse_conditional()?;
+        Ok(Expr::Conditional(Box::new(condition), Box::new(then), Box::new(otherwise)))
+    }
+
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expr, ParseError> {
+        let mut left = self.parse_unary()?;
+
+        while let Some(operator) = self.peek_operator().and_then(BinaryOperator::from_token) {
+            let precedence = operator.precedence();
+            if precedence <= min_precedence {
+                break;
             }
+            self.position += 1;
+            let right = self.parse_binary(precedence)?;
+            left = Expr::Binary(operator, Box::new(left), Box::new(right));
         }
-        Some("<=") => {
-            if let (Some(Value::Number(n1)), Value::Number(n2)) = (value1, value2) {
-                Value::Bool(n1.as_f64() <= n2.as_f64())
-            } else {
-                Value::Bool(false)
+
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        let operator = match self.peek_operator() {
+            Some("!") => UnaryOperator::Not,
+            Some("-") => UnaryOperator::Negate,
+            _ => return self.parse_primary(),
+        };
+        self.position += 1;
+        let operand = self.parse_unary()?;
+        Ok(Expr::Unary(operator, Box::new(operand)))
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, ParseError> {
+        let position = self.current_position();
+        let Some((token, range)) = self.tokens.get(self.position).cloned() else {
+            return Err(ParseError::new("Unexpected end of expression", position));
+        };
+        self.position += 1;
+
+        match token {
+            Token::Literal(value) => Ok(Expr::Literal(value)),
+            Token::Identifier(name) if self.peek_operator() == Some("(") => {
+                if name.contains(['.', '[']) {
+                    return Err(ParseError::new(format!("Invalid function name '{}'", name), range.start));
+                }
+                self.position += 1;
+                Ok(Expr::Call(name, self.parse_arguments()?))
+            }
+            Token::Identifier(path) => Path::parse(&path)
+                .map(Expr::Path)
+                .map_err(|error| ParseError::new(error.message, range.start + error.position)),
+            Token::Operator("(") => {
+                let expr = self.parse_conditional()?;
+                self.expect_operator(")")?;
+                Ok(expr)
             }
+            Token::Operator(operator) => Err(ParseError::new(format!("Unexpected operator '{}'", operator), position)),
         }
-        Some("&&") => {
-            let b1 = value_to_bool(value1);
-            let b2 = value_to_bool(Some(value2));
-            Value::Bool(b1 && b2)
+    }
+
+    // The comma separated arguments of a call, after its opening parenthesis.
+    fn parse_arguments(&mut self) -> Result<Vec<Expr>, ParseError> {
+        let mut args = Vec::new();
+        if self.peek_operator() == Some(")") {
+            self.position += 1;
+            return Ok(args);
         }
-        Some("||") => {
-            let b1 = value_to_bool(value1);
-            let b2 = value_to_bool(Some(value2));
-            Value::Bool(b1 || b2)
+        loop {
+            args.push(self.parse_conditional()?);
+            if self.peek_operator() != Some(",") {
+                self.expect_operator(")")?;
+                return Ok(args);
+            }
+            self.position += 1;
         }
-        Some("!") => {
-            let b = value_to_bool(Some(value2));
-            Value::Bool(!b)
+    }
+}
+
+// An expression that cannot be evaluated.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EvalError {
+    Parse(ParseError),
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvalError::Parse(error) => write!(f, "{}", error),
         }
-        _ => value2,
     }
 }
 
-// Parses a token into a Value.
-fn parse_value(token: &str, state: &Value) -> Value {
-    if let Ok(number) = token.parse::<f64>() {
-        let json_number = Number::from_f64(number).unwrap();
-        return Value::Number(json_number);
+impl std::error::Error for EvalError {}
+
+impl From<ParseError> for EvalError {
+    fn from(error: ParseError) -> Self {
+        EvalError::Parse(error)
     }
+}
 
-    if token.starts_with("\"") || token.starts_with("'") {
-        return Value::String(token[1..token.len() - 1].to_string());
+// Evaluates an expression using a state object into its resulting value.
+pub fn evaluate_expression(expression: &str, state: &Value) -> Result<Value, EvalError> {
+    let expr = parse(expression)?;
+    Ok(evaluate(&expr, state).into_owned())
+}
+
+// Safely evaluates an expression using a state object. The expression is a string that can contain
+// logical operators and dotted paths to properties in the state object. Invalid expressions are false.
+pub fn safe_evaluate_expression(expression: &str, state: &Value) -> bool {
+    match parse(expression) {
+        Ok(expr) => is_truthy(&evaluate(&expr, state)),
+        Err(_) => false,
     }
+}
+
+// Evaluates a syntax tree into a Value with the built-in functions, see `evaluate_with`.
+pub fn evaluate<'a>(expr: &'a Expr, state: &'a Value) -> Cow<'a, Value> {
+    evaluate_with(expr, state, builtin_functions())
+}
+
+// Evaluates a syntax tree against the state with the given functions, see `evaluate_in`.
+pub fn evaluate_with<'a>(expr: &'a Expr, state: &'a Value, functions: &Functions) -> Cow<'a, Value> {
+    evaluate_in(expr, Scope::new(&[ScopeFrame::Root(state)]), functions)
+}
 
-    match token {
-        "true" => Value::Bool(true),
-        "false" => Value::Bool(false),
-        _ => {
-            let path_value = find_value_by_dotted_path(token, state);
-            match path_value {
-                Some(value) => value.clone(),
-                None => Value::Null,
+// Evaluates a syntax tree into a Value. Missing paths evaluate to null. Literals and values found in
+// the scope are borrowed, only computed values are owned.
+pub fn evaluate_in<'a>(expr: &'a Expr, scope: Scope<'_, 'a>, functions: &Functions) -> Cow<'a, Value> {
+    let eval = |expr: &'a Expr| evaluate_in(expr, scope, functions);
+    match expr {
+        Expr::Literal(value) => Cow::Borrowed(value),
+        Expr::Path(path) => scope.lookup(path).unwrap_or(Cow::Owned(Value::Null)),
+        Expr::Unary(UnaryOperator::Not, operand) => Cow::Owned(Value::Bool(!is_truthy(&eval(operand)))),
+        Expr::Unary(UnaryOperator::Negate, operand) => Cow::Owned(number_to_value(-to_number(&eval(operand)))),
+        Expr::Binary(BinaryOperator::And, left, right) => {
+            Cow::Owned(Value::Bool(is_truthy(&eval(left)) && is_truthy(&eval(right))))
+        }
+        Expr::Binary(BinaryOperator::Or, left, right) => {
+            Cow::Owned(Value::Bool(is_truthy(&eval(left)) || is_truthy(&eval(right))))
+        }
+        Expr::Binary(BinaryOperator::Coalesce, left, right) => match eval(left) {
+            value if value.is_null() => eval(right),
+            value => value,
+        },
+        Expr::Conditional(condition, then, otherwise) => {
+            if is_truthy(&eval(condition)) {
+                eval(then)
+            } else {
+                eval(otherwise)
             }
         }
+        Expr::Binary(operator, left, right) => Cow::Owned(apply_operator(*operator, &eval(left), &eval(right))),
+        Expr::Call(name, args) => {
+            let args: Vec<Cow<Value>> = args.iter().map(eval).collect();
+            let args: Vec<&Value> = args.iter().map(Cow::as_ref).collect();
+            Cow::Owned(functions.call(name, &args))
+        }
     }
 }
 
+// Applies a comparison or arithmetic operator to two values. Equality compares values of the same type.
+// Ordering and arithmetic follow JavaScript's rules: `+` concatenates when either side is a string, array
+// or object, otherwise operands are converted to numbers. Results JSON cannot represent differ from
+// JavaScript: `1 / 0` and `'a' * 2` are null rather than Infinity and NaN, and number literals out of
+// range such as `2e400` fail to parse. `@btjs/eval-js` only compares and combines conditions, so
+// expressions using arithmetic are not evaluated the same way on the client yet.
+fn apply_operator(operator: BinaryOperator, value1: &Value, value2: &Value) -> Value {
+    match operator {
+        BinaryOperator::Equal => Value::Bool(values_equal(value1, value2)),
+        BinaryOperator::NotEqual => Value::Bool(!values_equal(value1, value2)),
+        BinaryOperator::Less => compare(value1, value2, |ordering| ordering.is_lt()),
+        BinaryOperator::LessEqual => compare(value1, value2, |ordering| ordering.is_le()),
+        BinaryOperator::Greater => compare(value1, value2, |ordering| ordering.is_gt()),
+        BinaryOperator::GreaterEqual => compare(value1, value2, |ordering| ordering.is_ge()),
+        BinaryOperator::Add if is_concatenation(value1) || is_concatenation(value2) => {
+            Value::String(to_text(value1) + &to_text(value2))
+        }
+        BinaryOperator::Add => number_to_value(to_number(value1) + to_number(value2)),
+        BinaryOperator::Subtract => number_to_value(to_number(value1) - to_number(value2)),
+        BinaryOperator::Multiply => number_to_value(to_number(value1) * to_number(value2)),
+        BinaryOperator::Divide => number_to_value(to_number(value1) / to_number(value2)),
+        BinaryOperator::Remainder => number_to_value(to_number(value1) % to_number(value2)),
+        BinaryOperator::And | BinaryOperator::Or | BinaryOperator::Coalesce => {
+            unreachable!("logical operators short-circuit in evaluate")
+        }
+    }
+}
+
+fn is_concatenation(value: &Value) -> bool {
+    matches!(value, Value::String(_) | Value::Array(_) | Value::Object(_))
+}
+
+pub(crate) fn values_equal(value1: &Value, value2: &Value) -> bool {
+    match (value1, value2) {
+        (Value::Number(n1), Value::Number(n2)) => n1.as_f64() == n2.as_f64(),
+        _ => value1 == value2,
+    }
+}
+
+// Strings compare alphabetically, everything else compares as numbers. NaN is never ordered.
+fn compare(value1: &Value, value2: &Value, matches: fn(Ordering) -> bool) -> Value {
+    let ordering = match (value1, value2) {
+        (Value::String(s1), Value::String(s2)) => Some(s1.cmp(s2)),
+        _ => to_number(value1).partial_cmp(&to_number(value2)),
+    };
+    Value::Bool(ordering.is_some_and(matches))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -362,4 +689,288 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_parse_precedence() {
+        let path = |p: &str| Box::new(Expr::Path(Path::parse(p).unwrap()));
+        assert_eq!(
+            parse("a || b && c"),
+            Ok(Expr::Binary(
+                BinaryOperator::Or,
+                path("a"),
+                Box::new(Expr::Binary(BinaryOperator::And, path("b"), path("c")))
+            ))
+        );
+        assert_eq!(
+            parse("!a == b"),
+            Ok(Expr::Binary(
+                BinaryOperator::Equal,
+                Box::new(Expr::Unary(UnaryOperator::Not, path("a"))),
+                path("b")
+            ))
+        );
+        assert_eq!(
+            parse("a > 1 == true"),
+            Ok(Expr::Binary(
+                BinaryOperator::Equal,
+                Box::new(Expr::Binary(
+                    BinaryOperator::Greater,
+                    path("a"),
+                    Box::new(Expr::Literal(serde_json::json!(1)))
+                )),
+                Box::new(Expr::Literal(Value::Bool(true)))
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let test_cases = vec![
+            ("name.first &&", "Unexpected end of expression", 13),
+            ("(a || b", "Expected ')'", 7),
+            ("a b", "Unexpected 'b'", 2),
+            ("a == 'b", "Unterminated string", 5),
+            ("a # b", "Unexpected character '#'", 2),
+            ("3px > 1", "Invalid number '3px'", 0),
+            ("a && ) b", "Unexpected operator ')'", 5),
+            ("a && b..c", "Empty segment in path 'b..c'", 7),
+            ("items[0 > 1", "Unterminated '[' in '[0 > 1'", 5),
+            ("items[x] > 1", "Invalid index in path 'items[x]'", 6),
+            ("upper(name", "Expected ')'", 10),
+            ("len(a,)", "Unexpected operator ')'", 6),
+            ("name.upper()", "Invalid function name 'name.upper'", 0),
+        ];
+
+        for (expression, message, position) in test_cases {
+            assert_eq!(
+                parse(expression),
+                Err(ParseError {
+                    message: message.to_string(),
+                    position,
+                }),
+                "Failed on expression: {}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn test_safe_evaluate_expression_precedence() {
+        let data = serde_json::json!({
+            "yes": true,
+            "no": false,
+            "count": 3,
+            "name": "John"
+        });
+
+        let test_cases = vec![
+            ("yes || no && no", true),
+            ("no && no || yes", true),
+            ("(yes || no) && no", false),
+            ("!no == yes", true),
+            ("!(no == yes)", true),
+            ("!!yes", true),
+            ("count != 3", false),
+            ("count != 4", true),
+            ("name != 'John'", false),
+            ("name != \"Jane\"", true),
+            ("count > 1 && count < 5", true),
+            ("count >= 3 == true", true),
+            ("missing == null", true),
+            ("missing != null || count <= 2", false),
+        ];
+
+        for (expression, expected) in test_cases {
+            assert_eq!(
+                safe_evaluate_expression(expression, &data),
+                expected,
+                "Failed on expression: {}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn test_evaluate_arithmetic() {
+        let data = serde_json::json!({
+            "items": [1, 2, 3],
+            "done": [1],
+            "price": 12.5,
+            "qty": 8,
+            "name": "John",
+            "count": "4"
+        });
+
+        let test_cases = vec![
+            ("1 + 2 * 3", serde_json::json!(7)),
+            ("(1 + 2) * 3", serde_json::json!(9)),
+            ("10 - 4 - 3", serde_json::json!(3)),
+            ("7 % 4 + 8 / 16", serde_json::json!(3.5)),
+            ("-qty + 1", serde_json::json!(-7)),
+            ("- -qty", serde_json::json!(8)),
+            ("price * qty", serde_json::json!(100)),
+            ("items.length - done.length", serde_json::json!(2)),
+            ("name.length * 2", serde_json::json!(8)),
+            ("name + ' ' + 'Doe'", serde_json::json!("John Doe")),
+            ("'n' + qty", serde_json::json!("n8")),
+            ("1 + 2 + 'a'", serde_json::json!("3a")),
+            ("'a' + 1 + 2", serde_json::json!("a12")),
+            ("count * 2", serde_json::json!(8)),
+            ("count + 1", serde_json::json!("41")),
+            ("true + 1", serde_json::json!(2)),
+            ("missing + 1", serde_json::json!(1)),
+            ("name * 2", Value::Null),
+            ("1 / 0", Value::Null),
+            ("0.1 + 0.2", serde_json::json!(0.30000000000000004)),
+        ];
+
+        for (expression, expected) in test_cases {
+            assert_eq!(
+                evaluate(&parse(expression).unwrap(), &data).into_owned(),
+                expected,
+                "Failed on expression: {}",
+                expression
+            );
+        }
+
+        let test_cases = vec![
+            ("items.length - done.length > 0", true),
+            ("price * qty >= 100", true),
+            ("price * qty > 100", false),
+            ("-price < 0", true),
+            ("'apple' < 'banana'", true),
+            ("count > 3", true),
+            ("name > 3", false),
+            ("!qty - 8", true),
+        ];
+
+        for (expression, expected) in test_cases {
+            assert_eq!(
+                safe_evaluate_expression(expression, &data),
+                expected,
+                "Failed on expression: {}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn test_evaluate_expression() {
+        let data = serde_json::json!({
+            "items": ["a", "b"],
+            "name": "John"
+        });
+
+        assert_eq!(evaluate_expression("items.length", &data), Ok(serde_json::json!(2)));
+        assert_eq!(evaluate_expression("'Hi ' + name", &data), Ok(serde_json::json!("Hi John")));
+        assert_eq!(evaluate_expression("items.length > 1 && name", &data), Ok(Value::Bool(true)));
+        assert_eq!(evaluate_expression("missing", &data), Ok(Value::Null));
+        assert_eq!(
+            evaluate_expression("name +", &data),
+            Err(EvalError::Parse(ParseError {
+                message: "Unexpected end of expression".to_string(),
+                position: 6,
+            }))
+        );
+    }
+
+    #[test]
+    fn test_evaluate_conditional_and_coalesce() {
+        let data = serde_json::json!({
+            "count": 2,
+            "name": "John",
+            "nickname": null,
+            "empty": "",
+            "items": []
+        });
+
+        let test_cases = vec![
+            ("count > 1 ? 'many' : 'one'", serde_json::json!("many")),
+            ("count > 2 ? 'many' : 'one'", serde_json::json!("one")),
+            ("count == 0 ? 'none' : count == 1 ? 'one' : 'many'", serde_json::json!("many")),
+            ("(count > 1 ? 10 : 20) + 1", serde_json::json!(11)),
+            ("items.length ? items.length : 'empty'", serde_json::json!("empty")),
+            ("nickname ?? name", serde_json::json!("John")),
+            ("missing ?? nickname ?? 'anonymous'", serde_json::json!("anonymous")),
+            ("empty ?? 'fallback'", serde_json::json!("")),
+            ("count ?? 0 + 5", serde_json::json!(2)),
+            ("nickname ?? count > 1 ? 'a' : 'b'", serde_json::json!("a")),
+        ];
+
+        for (expression, expected) in test_cases {
+            assert_eq!(
+                evaluate_expression(expression, &data),
+                Ok(expected),
+                "Failed on expression: {}",
+                expression
+            );
+        }
+
+        assert_eq!(parse("a ? b").map_err(|error| error.message), Err("Expected ':'".to_string()));
+        assert_eq!(parse("a ?? ").map_err(|error| error.message), Err("Unexpected end of expression".to_string()));
+    }
+
+    #[test]
+    fn test_evaluate_bracket_paths() {
+        let data = serde_json::json!({
+            "items": [
+                { "title": "first", "done": true },
+                { "title": "last", "done": false }
+            ],
+            "map": { "key with dots": "value", "a]b": 2 }
+        });
+
+        let test_cases = vec![
+            ("items[0].title", serde_json::json!("first")),
+            ("items.1.title", serde_json::json!("last")),
+            ("items[-1].title + '!'", serde_json::json!("last!")),
+            ("map[\"key with dots\"]", serde_json::json!("value")),
+            ("map['a]b'] * 2", serde_json::json!(4)),
+            ("items[0].done && !items[1].done", serde_json::json!(true)),
+            ("items[5].title ?? 'none'", serde_json::json!("none")),
+        ];
+
+        for (expression, expected) in test_cases {
+            assert_eq!(
+                evaluate_expression(expression, &data),
+                Ok(expected),
+                "Failed on expression: {}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn test_evaluate_functions() {
+        let data = serde_json::json!({
+            "name": "  John ",
+            "tags": ["new", "sale"],
+            "price": 1234.5
+        });
+
+        let test_cases = vec![
+            ("upper(trim(name))", serde_json::json!("JOHN")),
+            ("len(tags) + 1", serde_json::json!(3)),
+            ("contains(tags, 'sale') ? 'On sale' : ''", serde_json::json!("On sale")),
+            ("startsWith(trim(name), 'Jo')", serde_json::json!(true)),
+            ("format_number(price * 2, 2)", serde_json::json!("2,469.00")),
+            ("round(price)", serde_json::json!(1235)),
+            ("unknown(1)", Value::Null),
+        ];
+
+        for (expression, expected) in test_cases {
+            assert_eq!(
+                evaluate_expression(expression, &data),
+                Ok(expected),
+                "Failed on expression: {}",
+                expression
+            );
+        }
+
+        let mut functions = Functions::new();
+        functions.register("greet", |args| Value::String(format!("Hello {}", to_text(args[0]).trim())));
+        let expr = parse("greet(name)").unwrap();
+        assert_eq!(evaluate_with(&expr, &data, &functions).into_owned(), serde_json::json!("Hello John"));
+        assert_eq!(evaluate(&expr, &data).into_owned(), Value::Null);
+    }
 }
@@ -0,0 +1,209 @@
+use crate::functions::format_number;
+use crate::values::*;
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+
+// Formats a signal value before it is written, e.g. `currency:USD` calls the `currency` filter with the
+// arguments `["USD"]`. Filters are chained in order, returning null falls back to the default value.
+pub trait Filter: Send + Sync {
+    fn apply(&self, value: &Value, args: &[&str]) -> Value;
+}
+
+impl<F> Filter for F
+where
+    F: Fn(&Value, &[&str]) -> Value + Send + Sync,
+{
+    fn apply(&self, value: &Value, args: &[&str]) -> Value {
+        self(value, args)
+    }
+}
+
+// The filters signals can use by name. It starts with the built-in filters, host applications register
+// their own before rendering, replacing a built-in when they use the same name.
+#[derive(Clone)]
+pub struct Filters {
+    filters: HashMap<String, Arc<dyn Filter>>,
+}
+
+impl Filters {
+    pub fn new() -> Self {
+        let mut filters = Filters {
+            filters: HashMap::new(),
+        };
+        filters.register("number", number);
+        filters.register("currency", currency);
+        filters.register("date", date);
+        filters.register("truncate", truncate);
+        filters.register("json", |value: &Value, _: &[&str]| Value::String(value.to_string()));
+        filters
+    }
+
+    pub fn register(&mut self, name: impl Into<String>, filter: impl Filter + 'static) {
+        self.filters.insert(name.into(), Arc::new(filter));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn Filter>> {
+        self.filters.get(name)
+    }
+
+    // Applies each `name:arg:...` filter in turn. Unknown filters leave the value unchanged.
+    pub fn apply_all<'a>(&self, specs: &[String], value: Cow<'a, Value>) -> Cow<'a, Value> {
+        specs.iter().fold(value, |value, spec| {
+            let mut parts = spec.split(':');
+            let name = parts.next().unwrap_or_default().trim();
+            let args: Vec<&str> = parts.collect();
+            match self.get(name) {
+                Some(filter) => Cow::Owned(filter.apply(&value, &args)),
+                None => value,
+            }
+        })
+    }
+}
+
+impl Default for Filters {
+    fn default() -> Self {
+        Filters::new()
+    }
+}
+
+// `number` and `number:2`, thousands separators and optionally a fixed number of decimals.
+fn number(value: &Value, args: &[&str]) -> Value {
+    let decimals = args.first().map_or(Value::Null, |decimals| Value::String(decimals.to_string()));
+    format_number(value, &decimals)
+}
+
+// `currency:USD`, the amount with the symbol of the currency and its usual number of decimals.
+// Currencies without a well known symbol are prefixed with their code.
+fn currency(value: &Value, args: &[&str]) -> Value {
+    let code = args.first().map_or("USD", |code| code.trim());
+    let (symbol, decimals) = match code.to_ascii_uppercase().as_str() {
+        "USD" => ("$", 2),
+        "EUR" => ("€", 2),
+        "GBP" => ("£", 2),
+        "JPY" => ("¥", 0),
+        "INR" => ("₹", 2),
+        _ => ("", 2),
+    };
+
+    let n = to_number(value);
+    let Value::String(amount) = format_number(&number_to_value(n.abs()), &Value::from(decimals)) else {
+        return Value::Null;
+    };
+    let sign = if n < 0.0 && amount.bytes().any(|b| matches!(b, b'1'..=b'9')) { "-" } else { "" };
+    match symbol {
+        "" => Value::String(format!("{}{} {}", sign, code, amount)),
+        _ => Value::String(format!("{}{}{}", sign, symbol, amount)),
+    }
+}
+
+// `date` and `date:<strftime pattern>`, formats an ISO 8601 date or date time without depending on the
+// locale of the server. The time is shown in the offset it was written with. Values that are not ISO
+// dates are left unchanged.
+fn date(value: &Value, args: &[&str]) -> Value {
+    let Value::String(s) = value else {
+        return value.clone();
+    };
+    // The pattern may contain `:` itself, e.g. `date:%H:%M`.
+    let pattern = args.join(":");
+
+    let mut formatted = String::new();
+    let result = if let Ok(date_time) = DateTime::parse_from_rfc3339(s) {
+        write!(formatted, "{}", date_time.format(or(&pattern, "%Y-%m-%d %H:%M")))
+    } else if let Ok(date_time) = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f") {
+        write!(formatted, "{}", date_time.format(or(&pattern, "%Y-%m-%d %H:%M")))
+    } else if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        write!(formatted, "{}", date.format(or(&pattern, "%Y-%m-%d")))
+    } else {
+        return value.clone();
+    };
+
+    // An invalid pattern fails to format rather than panicking.
+    match result {
+        Ok(()) => Value::String(formatted),
+        Err(_) => value.clone(),
+    }
+}
+
+fn or<'a>(pattern: &'a str, default: &'a str) -> &'a str {
+    if pattern.is_empty() {
+        default
+    } else {
+        pattern
+    }
+}
+
+// `truncate:20` and `truncate:20:...`, shortens text to a number of characters followed by `…` or the
+// given suffix.
+fn truncate(value: &Value, args: &[&str]) -> Value {
+    let text = match value {
+        Value::String(s) => Cow::Borrowed(s.as_str()),
+        _ => Cow::Owned(to_text(value)),
+    };
+    let Some(length) = args.first().and_then(|length| length.trim().parse::<usize>().ok()) else {
+        return Value::String(text.into_owned());
+    };
+    match text.char_indices().nth(length) {
+        Some((end, _)) => {
+            let suffix = if args.len() > 1 { args[1..].join(":") } else { "…".to_string() };
+            Value::String(format!("{}{}", &text[..end], suffix))
+        }
+        None => Value::String(text.into_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_builtin_filters() {
+        let filters = Filters::new();
+        let test_cases = vec![
+            ("number", json!(1234567.891), json!("1,234,567.891")),
+            ("number:2", json!(1234.5), json!("1,234.50")),
+            ("currency:USD", json!(1234.5), json!("$1,234.50")),
+            ("currency:eur", json!("-3"), json!("-€3.00")),
+            ("currency:JPY", json!(1500.4), json!("¥1,500")),
+            ("currency:CHF", json!(10), json!("CHF 10.00")),
+            ("currency", json!(-0.001), json!("$0.00")),
+            ("date", json!("2024-03-05T14:30:00+02:00"), json!("2024-03-05 14:30")),
+            ("date", json!("2024-03-05T14:30:00.250"), json!("2024-03-05 14:30")),
+            ("date:%d %b %Y", json!("2024-03-05"), json!("05 Mar 2024")),
+            ("date:%H:%M:%S", json!("2024-03-05T09:05:07Z"), json!("09:05:07")),
+            ("date:%Q", json!("2024-03-05"), json!("2024-03-05")),
+            ("date", json!("yesterday"), json!("yesterday")),
+            ("truncate:5", json!("Hello world"), json!("Hello…")),
+            ("truncate:5:...", json!("Hello world"), json!("Hello...")),
+            ("truncate:20", json!("Hello world"), json!("Hello world")),
+            ("truncate:2", json!("héllo"), json!("hé…")),
+            ("json", json!({ "a": [1, "b"] }), json!("{\"a\":[1,\"b\"]}")),
+            ("unknown:1", json!(1), json!(1)),
+        ];
+
+        for (spec, value, expected) in test_cases {
+            assert_eq!(
+                filters.apply_all(&[spec.to_string()], Cow::Owned(value)).into_owned(),
+                expected,
+                "Failed on filter: {}",
+                spec
+            );
+        }
+    }
+
+    #[test]
+    fn test_filter_chain() {
+        let mut filters = Filters::new();
+        filters.register("percent", |value: &Value, _: &[&str]| number_to_value(to_number(value) * 100.0));
+
+        let specs = ["percent".to_string(), "number:1".to_string()];
+        assert_eq!(filters.apply_all(&specs, Cow::Owned(json!(0.256))).into_owned(), json!("25.6"));
+
+        let value = json!("unchanged");
+        assert!(matches!(filters.apply_all(&[], Cow::Borrowed(&value)), Cow::Borrowed(_)));
+    }
+}
@@ -0,0 +1,182 @@
+use crate::expression::values_equal;
+use crate::values::*;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock};
+
+// A pure function that can be called from an expression, missing arguments are null.
+pub type Function = Arc<dyn Fn(&[&Value]) -> Value + Send + Sync>;
+
+// The functions expressions can call by name. It starts with the built-in helpers, host applications
+// register their own before rendering, replacing a built-in when they use the same name.
+#[derive(Clone)]
+pub struct Functions {
+    functions: HashMap<String, Function>,
+}
+
+impl Functions {
+    pub fn new() -> Self {
+        let mut functions = Functions {
+            functions: HashMap::new(),
+        };
+        functions.register("len", |args| len(arg(args, 0)));
+        functions.register("upper", |args| Value::String(text(arg(args, 0)).to_uppercase()));
+        functions.register("lower", |args| Value::String(text(arg(args, 0)).to_lowercase()));
+        functions.register("trim", |args| Value::String(text(arg(args, 0)).trim().to_string()));
+        functions.register("contains", |args| Value::Bool(contains(arg(args, 0), arg(args, 1))));
+        functions.register("startsWith", |args| {
+            Value::Bool(text(arg(args, 0)).starts_with(&text(arg(args, 1))))
+        });
+        functions.register("round", |args| round(arg(args, 0), arg(args, 1)));
+        functions.register("format_number", |args| format_number(arg(args, 0), arg(args, 1)));
+        functions
+    }
+
+    pub fn register(&mut self, name: impl Into<String>, function: impl Fn(&[&Value]) -> Value + Send + Sync + 'static) {
+        self.functions.insert(name.into(), Arc::new(function));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Function> {
+        self.functions.get(name)
+    }
+
+    // Calls a function by name, unknown functions evaluate to null like missing paths do.
+    pub fn call(&self, name: &str, args: &[&Value]) -> Value {
+        match self.get(name) {
+            Some(function) => function(args),
+            None => Value::Null,
+        }
+    }
+}
+
+impl Default for Functions {
+    fn default() -> Self {
+        Functions::new()
+    }
+}
+
+// The built-in functions, used when evaluating without a protocol.
+pub fn builtin_functions() -> &'static Functions {
+    static BUILTIN_FUNCTIONS: LazyLock<Functions> = LazyLock::new(Functions::new);
+    &BUILTIN_FUNCTIONS
+}
+
+fn arg<'a>(args: &[&'a Value], index: usize) -> &'a Value {
+    args.get(index).copied().unwrap_or(&Value::Null)
+}
+
+// The text of a value for string helpers, where null is empty rather than "null".
+fn text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        _ => to_text(value),
+    }
+}
+
+// Strings count UTF-16 code units like `.length` and JavaScript do.
+fn len(value: &Value) -> Value {
+    let length = match value {
+        Value::String(s) => s.encode_utf16().count(),
+        Value::Array(array) => array.len(),
+        Value::Object(map) => map.len(),
+        _ => return Value::Null,
+    };
+    Value::Number(length.into())
+}
+
+// Whether an array has an item, a string has a substring or an object has a key.
+fn contains(haystack: &Value, needle: &Value) -> bool {
+    match haystack {
+        Value::Array(array) => array.iter().any(|item| values_equal(item, needle)),
+        Value::String(s) => s.contains(&text(needle)),
+        Value::Object(map) => map.contains_key(&text(needle)),
+        _ => false,
+    }
+}
+
+// Rounds half up like JavaScript's `Math.round`, to the given number of decimals.
+fn round(value: &Value, decimals: &Value) -> Value {
+    let factor = 10f64.powi(to_number(decimals) as i32);
+    number_to_value((to_number(value) * factor + 0.5).floor() / factor)
+}
+
+// Formats a number with `,` thousands separators and optionally a fixed number of decimals.
+pub(crate) fn format_number(value: &Value, decimals: &Value) -> Value {
+    let n = to_number(value);
+    if !n.is_finite() {
+        return Value::Null;
+    }
+    let digits = match decimals {
+        Value::Null => to_text(&number_to_value(n.abs())),
+        _ => format!("{:.*}", to_number(decimals).clamp(0.0, 20.0) as usize, n.abs()),
+    };
+    let (integer, fraction) = digits.split_at(digits.find('.').unwrap_or(digits.len()));
+
+    let mut formatted = String::with_capacity(digits.len() + integer.len() / 3 + 1);
+    if n < 0.0 && digits.bytes().any(|b| matches!(b, b'1'..=b'9')) {
+        formatted.push('-');
+    }
+    for (i, c) in integer.chars().enumerate() {
+        if i > 0 && (integer.len() - i) % 3 == 0 {
+            formatted.push(',');
+        }
+        formatted.push(c);
+    }
+    formatted.push_str(fraction);
+    Value::String(formatted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_builtin_functions() {
+        let functions = Functions::new();
+        let test_cases = vec![
+            ("len", vec![json!("héllo")], json!(5)),
+            ("len", vec![json!("a👍")], json!(3)),
+            ("len", vec![json!([1, 2])], json!(2)),
+            ("len", vec![json!({ "a": 1 })], json!(1)),
+            ("len", vec![json!(3)], Value::Null),
+            ("upper", vec![json!("abc")], json!("ABC")),
+            ("lower", vec![json!("ABC")], json!("abc")),
+            ("lower", vec![], json!("")),
+            ("trim", vec![json!("  a b ")], json!("a b")),
+            ("contains", vec![json!([1, "a"]), json!(1.0)], json!(true)),
+            ("contains", vec![json!([1, "a"]), json!("b")], json!(false)),
+            ("contains", vec![json!("hello"), json!("ell")], json!(true)),
+            ("contains", vec![json!({ "a": 1 }), json!("a")], json!(true)),
+            ("contains", vec![Value::Null, json!("a")], json!(false)),
+            ("startsWith", vec![json!("hello"), json!("he")], json!(true)),
+            ("startsWith", vec![json!("hello"), json!("lo")], json!(false)),
+            ("round", vec![json!(2.5)], json!(3)),
+            ("round", vec![json!(-2.5)], json!(-2)),
+            ("round", vec![json!(1.23456), json!(2)], json!(1.23)),
+            ("format_number", vec![json!(1234567.891)], json!("1,234,567.891")),
+            ("format_number", vec![json!(1234567.891), json!(2)], json!("1,234,567.89")),
+            ("format_number", vec![json!(-1000), json!(0)], json!("-1,000")),
+            ("format_number", vec![json!(-0.001), json!(2)], json!("0.00")),
+            ("format_number", vec![json!(999)], json!("999")),
+            ("format_number", vec![json!("abc")], Value::Null),
+            ("missing", vec![json!(1)], Value::Null),
+        ];
+
+        for (name, args, expected) in test_cases {
+            let args: Vec<&Value> = args.iter().collect();
+            assert_eq!(functions.call(name, &args), expected, "Failed on {}({:?})", name, args);
+        }
+    }
+
+    #[test]
+    fn test_register_function() {
+        let mut functions = Functions::new();
+        functions.register("double", |args| number_to_value(to_number(arg(args, 0)) * 2.0));
+        functions.register("upper", |_| json!("replaced"));
+
+        assert_eq!(functions.call("double", &[&json!(21)]), json!(42));
+        assert_eq!(functions.call("upper", &[&json!("a")]), json!("replaced"));
+        assert!(builtin_functions().get("double").is_none());
+    }
+}
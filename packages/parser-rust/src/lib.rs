@@ -1,4 +1,11 @@
+pub mod compiled;
+pub mod escape;
 pub mod expression;
+pub mod filters;
+pub mod functions;
 pub mod parser;
 pub mod protocol;
+pub mod scope;
+pub mod shape;
+pub mod validate;
 pub mod values;
\ No newline at end of file
@@ -1,100 +1,543 @@
+use crate::compiled::*;
+use crate::escape::*;
 use crate::expression::*;
 use crate::protocol::*;
+use crate::scope::*;
 use crate::values::*;
 use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt;
+use std::future::Future;
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
 
 pub trait ServerHandler {
     fn write(&mut self, value: &str);
     fn end(&mut self);
 }
 
+// Asynchronous counterpart of `ServerHandler` for writers that need to apply backpressure, such as
+// sockets, compressed streams or files. Implementations can use `async fn` for both methods.
+pub trait AsyncServerHandler: Send {
+    fn write(&mut self, value: &str) -> impl Future<Output = ()> + Send;
+    fn end(&mut self) -> impl Future<Output = ()> + Send;
+}
+
+// How rendering handles values it has to fall back on: a missing path, a repeat value that is not an
+// array or a repeat template missing from the protocol.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RenderPolicy {
+    // Falls back silently, writing the default value or nothing.
+    #[default]
+    Lenient,
+    // Falls back and returns a warning for each fallback.
+    Warn,
+    // Stops at the first fallback and returns it as an error. The output written so far is incomplete
+    // and the handler is not ended.
+    Strict,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RenderOptions {
+    pub policy: RenderPolicy,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RenderWarningKind {
+    // A path or expression of a signal, attribute or repeat has no value.
+    MissingValue,
+    // A repeat value that is not an array, rendered as an empty repeat.
+    NotAnArray,
+    // A repeat template missing from the protocol templates.
+    UnknownTemplate,
+}
+
+// A fallback taken while rendering, `value` is the path, expression or template name of the stream.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderWarning {
+    pub kind: RenderWarningKind,
+    pub value: String,
+}
+
+impl fmt::Display for RenderWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            RenderWarningKind::MissingValue => write!(f, "Missing value '{}'", self.value),
+            RenderWarningKind::NotAnArray => write!(f, "Repeat value '{}' is not an array", self.value),
+            RenderWarningKind::UnknownTemplate => write!(f, "Unknown template '{}'", self.value),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RenderError {
+    // The protocol failed to compile, protocols are compiled strictly with the strict policy.
+    Compile(CompileError),
+    Render(RenderWarning),
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::Compile(error) => error.fmt(f),
+            RenderError::Render(warning) => warning.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+// Compiles and renders the protocol, prefer `handle_compiled_btr` when rendering a protocol more than once.
 pub fn handle_btr(protocol: BuildTimeRenderingProtocol, state: Value, server_handler: &mut dyn ServerHandler) {
-    for stream in protocol.streams {
-        match stream {
-            BuildTimeRenderingStream::Attribute(attribute_stream) => {
-                let value = find_value_by_dotted_path(&attribute_stream.value, &state);
-                let value_string = match value {
-                    Some(Value::String(s)) => s.clone(),
-                    Some(value) => value.to_string(),
-                    None => {
-                        if let Some(default_value) = attribute_stream.default_value.as_ref() {
-                            default_value.clone()
-                        } else {
-                            String::new()
+    let _ = handle_btr_with_options(protocol, state, server_handler, &RenderOptions::default());
+}
+
+pub fn handle_btr_with_options(
+    protocol: BuildTimeRenderingProtocol,
+    state: Value,
+    server_handler: &mut dyn ServerHandler,
+    options: &RenderOptions,
+) -> Result<Vec<RenderWarning>, RenderError> {
+    let protocol = compile_for(protocol, options)?;
+    handle_compiled_btr_with_options(&protocol, &state, server_handler, options)
+}
+
+pub fn handle_compiled_btr(protocol: &CompiledProtocol, state: &Value, server_handler: &mut dyn ServerHandler) {
+    let _ = handle_compiled_btr_with_options(protocol, state, server_handler, &RenderOptions::default());
+}
+
+pub fn handle_compiled_btr_with_options(
+    protocol: &CompiledProtocol,
+    state: &Value,
+    server_handler: &mut dyn ServerHandler,
+    options: &RenderOptions,
+) -> Result<Vec<RenderWarning>, RenderError> {
+    let mut sink = SyncSink(server_handler);
+    let render = pin!(render(protocol, state, &mut sink, options.policy));
+
+    // Synchronous handlers never suspend, so the render completes on the first poll.
+    match render.poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(result) => result.map_err(RenderError::Render),
+        Poll::Pending => unreachable!("synchronous server handlers never suspend"),
+    }
+}
+
+// Renders the protocol, awaiting every write so the handler can apply backpressure.
+pub async fn handle_btr_async<H: AsyncServerHandler>(protocol: BuildTimeRenderingProtocol, state: Value, server_handler: &mut H) {
+    let _ = handle_btr_async_with_options(protocol, state, server_handler, &RenderOptions::default()).await;
+}
+
+pub async fn handle_btr_async_with_options<H: AsyncServerHandler>(
+    protocol: BuildTimeRenderingProtocol,
+    state: Value,
+    server_handler: &mut H,
+    options: &RenderOptions,
+) -> Result<Vec<RenderWarning>, RenderError> {
+    let protocol = compile_for(protocol, options)?;
+    handle_compiled_btr_async_with_options(&protocol, &state, server_handler, options).await
+}
+
+pub async fn handle_compiled_btr_async<H: AsyncServerHandler>(protocol: &CompiledProtocol, state: &Value, server_handler: &mut H) {
+    let _ = handle_compiled_btr_async_with_options(protocol, state, server_handler, &RenderOptions::default()).await;
+}
+
+pub async fn handle_compiled_btr_async_with_options<H: AsyncServerHandler>(
+    protocol: &CompiledProtocol,
+    state: &Value,
+    server_handler: &mut H,
+    options: &RenderOptions,
+) -> Result<Vec<RenderWarning>, RenderError> {
+    render(protocol, state, &mut AsyncSink(server_handler), options.policy).await.map_err(RenderError::Render)
+}
+
+fn compile_for(protocol: BuildTimeRenderingProtocol, options: &RenderOptions) -> Result<CompiledProtocol, RenderError> {
+    match options.policy {
+        RenderPolicy::Strict => CompiledProtocol::try_from(protocol).map_err(RenderError::Compile),
+        _ => Ok(CompiledProtocol::compile_lenient(protocol)),
+    }
+}
+
+// Collects the fallbacks taken while rendering according to the policy.
+struct Warnings {
+    policy: RenderPolicy,
+    warnings: Vec<RenderWarning>,
+}
+
+impl Warnings {
+    fn report(&mut self, kind: RenderWarningKind, value: &str) -> Result<(), RenderWarning> {
+        let warning = || RenderWarning {
+            kind,
+            value: value.to_string(),
+        };
+        match self.policy {
+            RenderPolicy::Lenient => {}
+            RenderPolicy::Warn => self.warnings.push(warning()),
+            RenderPolicy::Strict => return Err(warning()),
+        }
+        Ok(())
+    }
+}
+
+// The renderer writes to a sink so both the synchronous and asynchronous handlers share one implementation.
+trait Sink {
+    async fn write(&mut self, value: &str);
+    async fn end(&mut self);
+}
+
+struct SyncSink<'a>(&'a mut dyn ServerHandler);
+
+impl Sink for SyncSink<'_> {
+    async fn write(&mut self, value: &str) {
+        self.0.write(value);
+    }
+
+    async fn end(&mut self) {
+        self.0.end();
+    }
+}
+
+struct AsyncSink<'a, H: AsyncServerHandler>(&'a mut H);
+
+impl<H: AsyncServerHandler> Sink for AsyncSink<'_, H> {
+    async fn write(&mut self, value: &str) {
+        self.0.write(value).await;
+    }
+
+    async fn end(&mut self) {
+        self.0.end().await;
+    }
+}
+
+// Where rendering resumes. Nested stream lists are rendered from this stack rather than recursively, as a
+// recursive async fn needs a boxed future that cannot be `Send` for every sink.
+enum Frame<'a> {
+    Streams(std::slice::Iter<'a, CompiledStream>),
+    Repeat(&'a BuildTimeRenderingStreamRepeat, std::iter::Enumerate<std::slice::Iter<'a, Value>>, usize),
+    // Closes an item once its template is rendered, projecting its values when the template is static.
+    Item(&'a BuildTimeRenderingStreamRepeat, Option<&'a CompiledTemplate>, &'a Value),
+}
+
+async fn render<S: Sink>(
+    protocol: &CompiledProtocol,
+    state: &Value,
+    server_handler: &mut S,
+    policy: RenderPolicy,
+) -> Result<Vec<RenderWarning>, RenderWarning> {
+    let mut warnings = Warnings {
+        policy,
+        warnings: Vec::new(),
+    };
+    let mut scopes = vec![ScopeFrame::Root(state)];
+    let mut stack = vec![Frame::Streams(protocol.streams.iter())];
+    let mut written_styles = HashSet::new();
+
+    while let Some(frame) = stack.last_mut() {
+        match frame {
+            Frame::Streams(streams) => {
+                let Some(stream) = streams.next() else {
+                    stack.pop();
+                    continue;
+                };
+                if let Some(frame) = render_stream(protocol, stream, Scope::new(&scopes), server_handler, &mut warnings).await? {
+                    stack.push(frame);
+                }
+            }
+            Frame::Repeat(repeat_stream, items, length) => {
+                let (repeat_stream, length) = (*repeat_stream, *length);
+                let Some((index, item)) = items.next() else {
+                    stack.pop();
+                    continue;
+                };
+
+                let tag = &repeat_stream.template;
+                let template = protocol.templates.get(tag);
+                let style = template.and_then(|t| t.style.as_ref());
+                let shadow_root = template.map_or("open", |t| match t.shadow_root_mode {
+                    BuildTimeRenderingShadowRootMode::Open => "open",
+                    BuildTimeRenderingShadowRootMode::Closed => "closed",
+                    BuildTimeRenderingShadowRootMode::None => "",
+                });
+
+                if shadow_root.is_empty() {
+                    // Light DOM styles apply to the whole page, so they are scoped to the element and only
+                    // written before its first item.
+                    if let Some(style) = style.filter(|_| written_styles.insert(tag.as_str())) {
+                        let style = scope_host_selectors(style);
+                        server_handler.write(&format!("<style>@scope ({}) {{ {} }}</style>", tag, style)).await;
+                    }
+                    server_handler.write(&format!("<{}>", tag)).await;
+                } else {
+                    let delegates_focus = if template.is_some_and(|t| t.delegates_focus) { " shadowrootdelegatesfocus" } else { "" };
+                    server_handler.write(&format!("<{}><template shadowrootmode=\"{}\"{}>", tag, shadow_root, delegates_focus)).await;
+                    match (style, protocol.stylesheet_href(tag)) {
+                        (Some(_), Some(href)) => {
+                            server_handler.write(&format!("<link rel=\"stylesheet\" href=\"{}\">", href)).await;
                         }
+                        (Some(style), None) => server_handler.write(&format!("<style>{}</style>", style)).await,
+                        (None, _) => {}
                     }
-                };
-                server_handler.write(&format!("{}={}", attribute_stream.name, value_string));
+                }
+
+                scopes.push(ScopeFrame::Item { item, index, length });
+                stack.push(Frame::Item(repeat_stream, template, item));
+                match template {
+                    Some(template) if !template.streams.is_empty() => {
+                        stack.push(Frame::Streams(template.streams.iter()));
+                    }
+                    Some(template) if shadow_root.is_empty() => {
+                        let mut output = String::with_capacity(template.template.len());
+                        fill_slots(&template.parts, item, repeat_stream.html, &mut output);
+                        if !output.is_empty() {
+                            server_handler.write(&output).await;
+                        }
+                    }
+                    _ => {
+                        let template = template.map_or("", |t| t.template.as_str());
+                        server_handler.write(&format!("{}</template>", template)).await;
+                    }
+                }
             }
-            BuildTimeRenderingStream::Raw(raw_stream) => {
-                server_handler.write(&raw_stream.value);
+            Frame::Item(repeat_stream, template, item) => {
+                let (repeat_stream, template, item) = (*repeat_stream, *template, *item);
+                let dynamic = template.is_some_and(|t| !t.streams.is_empty());
+                let shadow = template.is_none_or(|t| t.shadow_root_mode != BuildTimeRenderingShadowRootMode::None);
+                match (dynamic, shadow) {
+                    (true, true) => server_handler.write("</template>").await,
+                    (false, true) => write_item_content(repeat_stream, item, server_handler).await,
+                    // Static light DOM items were projected into the slots of the template already.
+                    (_, false) => {}
+                }
+                server_handler.write(&format!("</{}>", repeat_stream.template)).await;
+                scopes.pop();
+                stack.pop();
             }
-            BuildTimeRenderingStream::Repeat(repeat_stream) => {
-                if let Some(value) = find_value_by_dotted_path(&repeat_stream.value, &state) {
-                    if let Value::Array(array) = value {
-                        for item in array {
-                            server_handler.write(&format!("<{}><template shadowrootmode=\"open\">", repeat_stream.template));
-                            if let Some(style) = protocol.templates.get(&repeat_stream.template).and_then(|t| t.style.as_ref()) {
-                                server_handler.write(&format!("<style>{}</style>", style));
-                            }
-
-                            let template = protocol.templates.get(&repeat_stream.template).unwrap().template.clone();
-                            server_handler.write(&format!("{}</template>", template));
-
-                            match item {
-                                Value::String(s) => {
-                                    server_handler.write(&s.to_string());
-                                },
-                                Value::Number(n) => {
-                                    server_handler.write(&n.to_string());
-                                },
-                                Value::Bool(b) => {
-                                    server_handler.write(&b.to_string());
-                                },
-                                Value::Array(arr) => {
-                                    let s: String = arr.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(",");
-                                    server_handler.write(&s);
-                                },
-                                Value::Object(map) => {
-                                    for (key, value) in map {
-                                        let value_str = match value {
-                                            Value::String(s) => s,
-                                            Value::Array(arr) => arr.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(","),
-                                            _ => value.to_string(),
-                                        };
-                                        server_handler.write(&format!("<span slot=\"{}\">{}</span>", key, value_str));
-                                    }
-                                },
-                                _ => {}
-                            }
-
-                            server_handler.write(&format!("</{}>", repeat_stream.template));
-                        }
+        }
+    }
+    server_handler.end().await;
+    Ok(warnings.warnings)
+}
+
+// Renders a single stream, returning the frame to continue with when it opens a nested stream list.
+async fn render_stream<'a, S: Sink>(
+    protocol: &'a CompiledProtocol,
+    stream: &'a CompiledStream,
+    scope: Scope<'_, 'a>,
+    server_handler: &mut S,
+    warnings: &mut Warnings,
+) -> Result<Option<Frame<'a>>, RenderWarning> {
+    match stream {
+        CompiledStream::Attribute(attribute_stream, binding) => {
+            let value = binding.resolve(scope, &protocol.functions);
+            if value.is_none() {
+                warnings.report(RenderWarningKind::MissingValue, &attribute_stream.value)?;
+            }
+            if let Some(attribute) = render_attribute(attribute_stream, value.as_deref()) {
+                server_handler.write(&attribute).await;
+            }
+        }
+        CompiledStream::Raw(raw_stream) => {
+            server_handler.write(&raw_stream.value).await;
+        }
+        CompiledStream::Repeat(repeat_stream, path, empty) => {
+            // Computed values are never arrays, so the items are always borrowed from the state.
+            match scope.lookup(path) {
+                Some(Cow::Borrowed(Value::Array(items))) if !items.is_empty() => {
+                    if !protocol.templates.contains_key(&repeat_stream.template) {
+                        warnings.report(RenderWarningKind::UnknownTemplate, &repeat_stream.template)?;
                     }
+                    return Ok(Some(Frame::Repeat(repeat_stream, items.iter().enumerate(), items.len())));
                 }
+                Some(Cow::Borrowed(Value::Array(_))) => {}
+                Some(_) => warnings.report(RenderWarningKind::NotAnArray, &repeat_stream.value)?,
+                None => warnings.report(RenderWarningKind::MissingValue, &repeat_stream.value)?,
             }
-            BuildTimeRenderingStream::Signal(signal_stream) => {
-                let value = find_value_by_dotted_path(&signal_stream.value, &state);
-                match value {
-                    Some(Value::String(s)) => server_handler.write(&s),
-                    Some(value) => server_handler.write(&value.to_string()),
-                    None => {
-                        if let Some(default_value) = signal_stream.default_value.as_ref() {
-                            server_handler.write(default_value);
-                        }
+            if !empty.is_empty() {
+                return Ok(Some(Frame::Streams(empty.iter())));
+            }
+        }
+        CompiledStream::Signal(signal_stream, binding) => {
+            let mut value = binding.resolve(scope, &protocol.functions);
+            if value.is_none() {
+                warnings.report(RenderWarningKind::MissingValue, &signal_stream.value)?;
+            }
+            if !signal_stream.filters.is_empty() {
+                value = value
+                    .map(|value| protocol.filters.apply_all(&signal_stream.filters, value))
+                    .filter(|value| !value.is_null());
+            }
+            match value.as_deref() {
+                Some(Value::String(s)) => server_handler.write(&text_content(s, signal_stream.html)).await,
+                Some(value) => server_handler.write(&text_content(&to_text(value), signal_stream.html)).await,
+                None => {
+                    if let Some(default_value) = signal_stream.default_value.as_ref() {
+                        server_handler.write(&text_content(default_value, signal_stream.html)).await;
                     }
                 }
             }
-            BuildTimeRenderingStream::When(when_stream) => {
-                let parts = parse_expression(&when_stream.value);
-                let value = safe_evaluate_expression(&parts.join(""), &state);
-                if !value {
-                    server_handler.write("style=\"display: none\"");
+        }
+        CompiledStream::When(when_stream, expr, streams, else_streams) => {
+            let condition = is_truthy(&evaluate_in(expr, scope, &protocol.functions));
+            if when_stream.mode == BuildTimeRenderingWhenMode::Structural || !streams.is_empty() || !else_streams.is_empty() {
+                let block = if condition { streams } else { else_streams };
+                return Ok(Some(Frame::Streams(block.iter())));
+            }
+            if let Some(attribute) = render_when_attribute(when_stream, condition) {
+                server_handler.write(&attribute).await;
+            }
+        }
+    }
+    Ok(None)
+}
+
+// Projects the values of an item rendered with a static template into the slots of the template.
+async fn write_item_content<S: Sink>(repeat_stream: &BuildTimeRenderingStreamRepeat, item: &Value, server_handler: &mut S) {
+    match item {
+        Value::String(s) => {
+            server_handler.write(&text_content(s, repeat_stream.html)).await;
+        },
+        Value::Number(n) => {
+            server_handler.write(&n.to_string()).await;
+        },
+        Value::Bool(b) => {
+            server_handler.write(&b.to_string()).await;
+        },
+        Value::Array(arr) => {
+            let s: String = arr.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(",");
+            server_handler.write(&text_content(&s, repeat_stream.html)).await;
+        },
+        Value::Object(map) => {
+            for (key, value) in map {
+                let value_str = match value {
+                    Value::String(s) => Cow::Borrowed(s.as_str()),
+                    Value::Array(arr) => Cow::Owned(arr.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(",")),
+                    _ => Cow::Owned(value.to_string()),
+                };
+                server_handler.write(&format!("<span slot=\"{}\">{}</span>", escape_attribute(key), text_content(&value_str, repeat_stream.html))).await;
+            }
+        },
+        _ => {}
+    }
+}
+
+// The text of an item value projected into a slot, converted like JavaScript does.
+fn slot_text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null | Value::Object(_) => None,
+        _ => Some(to_text(value)),
+    }
+}
+
+// Renders a static template in the light DOM, where there is no shadow root to project the item into the
+// slots: a named slot is replaced by that field of the item and the default slot by an item that is not
+// an object. Slots without a value render their fallback content.
+fn fill_slots(parts: &[TemplatePart], item: &Value, html: bool, output: &mut String) {
+    for part in parts {
+        match part {
+            TemplatePart::Html(template) => output.push_str(template),
+            TemplatePart::Slot(name, fallback) => {
+                let value = match name {
+                    Some(name) => item.get(name),
+                    None if !item.is_object() => Some(item),
+                    None => None,
+                };
+                match value.and_then(slot_text) {
+                    Some(text) => output.push_str(&text_content(&text, html)),
+                    None => fill_slots(fallback, item, html, output),
                 }
             }
         }
     }
-    server_handler.end();
+}
+
+// Light DOM styles are not in a shadow root, so `:host` selectors become `:scope` within `@scope`.
+fn scope_host_selectors(style: &str) -> Cow<'_, str> {
+    if !style.contains(":host") {
+        return Cow::Borrowed(style);
+    }
+    let mut output = String::with_capacity(style.len());
+    let mut rest = style;
+    while let Some(position) = rest.find(":host") {
+        output.push_str(&rest[..position]);
+        let after = &rest[position + 5..];
+        if after.starts_with('(') {
+            output.push_str(":scope:is");
+        } else if after.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '-') {
+            // `:host-context()` has no light DOM equivalent and is left as-is.
+            output.push_str(":host");
+        } else {
+            output.push_str(":scope");
+        }
+        rest = after;
+    }
+    output.push_str(rest);
+    Cow::Owned(output)
+}
+
+// Attributes whose presence alone means `true`, their value is ignored by the browser.
+const BOOLEAN_ATTRIBUTES: [&str; 25] = [
+    "allowfullscreen", "async", "autofocus", "autoplay", "checked", "controls", "default", "defer",
+    "disabled", "formnovalidate", "hidden", "inert", "ismap", "itemscope", "loop", "multiple", "muted",
+    "nomodule", "novalidate", "open", "playsinline", "readonly", "required", "reversed", "selected",
+];
+
+fn is_boolean_attribute(name: &str) -> bool {
+    BOOLEAN_ATTRIBUTES.iter().any(|attribute| attribute.eq_ignore_ascii_case(name))
+}
+
+// Renders an attribute following HTML semantics. Values are quoted and escaped. Boolean attributes render
+// the bare name when the value is truthy and are omitted otherwise, other attributes keep `true` and
+// `false` as values, as ARIA and enumerated attributes such as `aria-expanded` and `draggable` need them.
+// A missing or null value falls back to the default value, or omits the attribute when there is none.
+// Boolean attributes read the default as a literal, so `false`, `0` and the empty string omit them.
+fn render_attribute(attribute_stream: &BuildTimeRenderingStreamAttribute, value: Option<&Value>) -> Option<String> {
+    let name = &attribute_stream.name;
+    let value_string = match value {
+        Some(value) if is_boolean_attribute(name) => return is_truthy(value).then(|| name.clone()),
+        Some(Value::String(s)) => Cow::Borrowed(s.as_str()),
+        Some(value) => Cow::Owned(to_text(value)),
+        None => match &attribute_stream.default_value {
+            Some(default_value) if is_boolean_attribute(name) => {
+                let literal = serde_json::from_str(default_value);
+                return is_truthy(&literal.unwrap_or_else(|_| Value::String(default_value.clone()))).then(|| name.clone());
+            }
+            Some(default_value) => Cow::Borrowed(default_value.as_str()),
+            None => return None,
+        },
+    };
+
+    let value_string = if attribute_stream.html {
+        value_string
+    } else {
+        escape_attribute(&value_string)
+    };
+    Some(format!("{}=\"{}\"", name, value_string))
+}
+
+// Renders the attribute that hides the element of a when, merging with its own style in the `merge` mode.
+fn render_when_attribute(when_stream: &BuildTimeRenderingStreamWhen, condition: bool) -> Option<String> {
+    let style = when_stream.style.as_deref().map(|style| style.trim().trim_end_matches(';')).unwrap_or("");
+    let style = escape_attribute(style);
+    match when_stream.mode {
+        BuildTimeRenderingWhenMode::Merge if condition && style.is_empty() => None,
+        BuildTimeRenderingWhenMode::Merge if condition => Some(format!("style=\"{}\"", style)),
+        BuildTimeRenderingWhenMode::Merge if style.is_empty() => Some("style=\"display: none\"".to_string()),
+        BuildTimeRenderingWhenMode::Merge => Some(format!("style=\"{}; display: none\"", style)),
+        _ if condition => None,
+        BuildTimeRenderingWhenMode::Hidden => Some("hidden".to_string()),
+        BuildTimeRenderingWhenMode::Style | BuildTimeRenderingWhenMode::Structural => {
+            Some("style=\"display: none\"".to_string())
+        }
+    }
+}
+
+// Escapes a value written as text content, unless the stream opted into raw HTML output.
+fn text_content(value: &str, html: bool) -> Cow<'_, str> {
+    if html {
+        Cow::Borrowed(value)
+    } else {
+        escape_text(value)
+    }
 }
 
 #[cfg(test)]
@@ -128,6 +571,61 @@ mod tests {
         fn end(&mut self) {}
     }
 
+    struct AsyncTestServerHandler {
+        chunks: Vec<String>,
+        ended: bool,
+    }
+
+    impl AsyncServerHandler for AsyncTestServerHandler {
+        async fn write(&mut self, value: &str) {
+            tokio::task::yield_now().await;
+            self.chunks.push(value.to_string());
+        }
+
+        async fn end(&mut self) {
+            self.ended = true;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_btr_async() {
+        let protocol = BuildTimeRenderingProtocol {
+            streams: vec![
+                BuildTimeRenderingStream::Raw(
+                    BuildTimeRenderingStreamRaw {
+                        value: "Hello, ".to_string(),
+                    }
+                ),
+                BuildTimeRenderingStream::Signal(
+                    BuildTimeRenderingStreamSignal {
+                        value: "name".to_string(),
+                        default_value: None,
+                        html: false,
+                        expression: false,
+                        filters: Vec::new(),
+                    }
+                ),
+            ],
+            templates: HashMap::new(),
+        };
+        let state = json!({
+            "name": "world"
+        });
+        let mut server_handler = AsyncTestServerHandler {
+            chunks: Vec::new(),
+            ended: false,
+        };
+        // Spawning requires the render future to be `Send`.
+        let server_handler = tokio::spawn(async move {
+            handle_btr_async(protocol, state, &mut server_handler).await;
+            server_handler
+        })
+        .await
+        .unwrap();
+        assert_eq!(server_handler.chunks, vec!["Hello, ", "world"]);
+        assert!(server_handler.ended);
+    }
+
     #[test]
     fn test_handle_btr_raw() {
         let protocol = BuildTimeRenderingProtocol {
@@ -159,12 +657,18 @@ mod tests {
                     BuildTimeRenderingStreamSignal {
                         value: "a".to_string(),
                         default_value: Some("a".to_string()),
+                        html: false,
+                        expression: false,
+                        filters: Vec::new(),
                     }
                 ),
                 BuildTimeRenderingStream::Signal(
                     BuildTimeRenderingStreamSignal {
                         value: "b".to_string(),
                         default_value: Some("b".to_string()),
+                        html: false,
+                        expression: false,
+                        filters: Vec::new(),
                     }
                 ),
             ],
@@ -178,6 +682,251 @@ mod tests {
         assert_eq!(server_handler.get_output(), "appleb");
     }
 
+    #[test]
+    fn test_handle_btr_signal_escaped() {
+        let protocol = BuildTimeRenderingProtocol {
+            streams: vec![
+                BuildTimeRenderingStream::Signal(
+                    BuildTimeRenderingStreamSignal {
+                        value: "comment".to_string(),
+                        default_value: None,
+                        html: false,
+                        expression: false,
+                        filters: Vec::new(),
+                    }
+                ),
+                BuildTimeRenderingStream::Signal(
+                    BuildTimeRenderingStreamSignal {
+                        value: "missing".to_string(),
+                        default_value: Some("a < b".to_string()),
+                        html: false,
+                        expression: false,
+                        filters: Vec::new(),
+                    }
+                ),
+                BuildTimeRenderingStream::Signal(
+                    BuildTimeRenderingStreamSignal {
+                        value: "markup".to_string(),
+                        default_value: None,
+                        html: true,
+                        expression: false,
+                        filters: Vec::new(),
+                    }
+                ),
+            ],
+            templates: HashMap::new(),
+        };
+        let state = json!({
+            "comment": "<script>alert('x')</script>",
+            "markup": "<b>bold</b>"
+        });
+        let mut server_handler = TestServerHandler::new();
+        handle_btr(protocol, state, &mut server_handler);
+        assert_eq!(
+            server_handler.get_output(),
+            "&lt;script&gt;alert('x')&lt;/script&gt;a &lt; b<b>bold</b>"
+        );
+    }
+
+    fn attribute(name: &str, value: &str, default_value: Option<&str>) -> BuildTimeRenderingStream {
+        BuildTimeRenderingStream::Attribute(
+            BuildTimeRenderingStreamAttribute {
+                value: value.to_string(),
+                name: name.to_string(),
+                default_value: default_value.map(str::to_string),
+                html: false,
+                expression: false,
+            }
+        )
+    }
+
+    #[test]
+    fn test_handle_btr_attribute_html_semantics() {
+        let test_cases = vec![
+            (attribute("title", "label", None), "title=\"two words\""),
+            (attribute("title", "count", None), "title=\"0\""),
+            (attribute("title", "empty", None), "title=\"\""),
+            (attribute("title", "nothing", None), ""),
+            (attribute("title", "no", None), "title=\"false\""),
+            (attribute("title", "missing", None), ""),
+            (attribute("title", "missing", Some("fallback")), "title=\"fallback\""),
+            (attribute("data-active", "yes", None), "data-active=\"true\""),
+            (attribute("aria-expanded", "no", None), "aria-expanded=\"false\""),
+            (attribute("aria-hidden", "yes", None), "aria-hidden=\"true\""),
+            (attribute("draggable", "no", None), "draggable=\"false\""),
+            (attribute("spellcheck", "yes", None), "spellcheck=\"true\""),
+            (attribute("disabled", "yes", None), "disabled"),
+            (attribute("disabled", "no", None), ""),
+            (attribute("checked", "label", None), "checked"),
+            (attribute("hidden", "count", None), ""),
+            (attribute("hidden", "empty", None), ""),
+            (attribute("DISABLED", "count", None), ""),
+            (attribute("selected", "missing", Some("")), ""),
+            (attribute("selected", "missing", Some("selected")), "selected"),
+            (attribute("checked", "missing", Some("true")), "checked"),
+            (attribute("checked", "missing", Some("false")), ""),
+            (attribute("checked", "missing", Some("0")), ""),
+            (attribute("selected", "missing", None), ""),
+        ];
+        let state = json!({
+            "label": "two words",
+            "count": 0,
+            "empty": "",
+            "nothing": null,
+            "yes": true,
+            "no": false
+        });
+
+        for (stream, expected) in test_cases {
+            let protocol = BuildTimeRenderingProtocol {
+                streams: vec![stream],
+                templates: HashMap::new(),
+            };
+            let mut server_handler = TestServerHandler::new();
+            handle_btr(protocol, state.clone(), &mut server_handler);
+            assert_eq!(server_handler.get_output(), expected);
+        }
+    }
+
+    #[test]
+    fn test_handle_btr_path_and_expression_bindings() {
+        let protocol: BuildTimeRenderingProtocol = serde_json::from_value(json!({
+            "streams": [
+                { "type": "signal", "value": "nothing", "defaultValue": "-" },
+                { "type": "signal", "value": "nothing", "defaultValue": "-", "expression": true },
+                { "type": "raw", "value": "<p " },
+                { "type": "attribute", "name": "title", "value": "nothing", "defaultValue": "-" },
+                { "type": "raw", "value": " " },
+                { "type": "attribute", "name": "lang", "value": "nothing", "defaultValue": "-", "expression": true },
+                { "type": "raw", "value": ">" },
+                { "type": "signal", "value": "number" },
+                { "type": "signal", "value": "number", "expression": true },
+                { "type": "raw", "value": "<p " },
+                { "type": "attribute", "name": "title", "value": "number" },
+                { "type": "raw", "value": " " },
+                { "type": "attribute", "name": "lang", "value": "number", "expression": true },
+                { "type": "raw", "value": ">" }
+            ],
+            "templates": {}
+        }))
+        .unwrap();
+        let state = json!({ "nothing": null, "number": 1.0 });
+
+        let mut server_handler = TestServerHandler::new();
+        handle_btr(protocol, state, &mut server_handler);
+        assert_eq!(server_handler.get_output(), "--<p title=\"-\" lang=\"-\">11<p title=\"1\" lang=\"1\">");
+    }
+
+    #[test]
+    fn test_handle_btr_attribute_escaped() {
+        let protocol = BuildTimeRenderingProtocol {
+            streams: vec![
+                BuildTimeRenderingStream::Attribute(
+                    BuildTimeRenderingStreamAttribute {
+                        value: "title".to_string(),
+                        name: "title".to_string(),
+                        default_value: None,
+                        html: false,
+                        expression: false,
+                    }
+                )
+            ],
+            templates: HashMap::new(),
+        };
+        let state = json!({
+            "title": "\" onmouseover=\"alert(1)"
+        });
+        let mut server_handler = TestServerHandler::new();
+        handle_btr(protocol, state, &mut server_handler);
+        assert_eq!(server_handler.get_output(), "title=\"&quot; onmouseover=&quot;alert(1)\"");
+    }
+
+    #[test]
+    fn test_handle_btr_expressions() {
+        let protocol = BuildTimeRenderingProtocol {
+            streams: vec![
+                BuildTimeRenderingStream::Signal(
+                    BuildTimeRenderingStreamSignal {
+                        value: "items.length - done".to_string(),
+                        default_value: None,
+                        html: false,
+                        expression: true,
+                        filters: Vec::new(),
+                    }
+                ),
+                BuildTimeRenderingStream::Raw(
+                    BuildTimeRenderingStreamRaw {
+                        value: " ".to_string(),
+                    }
+                ),
+                BuildTimeRenderingStream::Signal(
+                    BuildTimeRenderingStreamSignal {
+                        value: "missing".to_string(),
+                        default_value: Some("none".to_string()),
+                        html: false,
+                        expression: true,
+                        filters: Vec::new(),
+                    }
+                ),
+                BuildTimeRenderingStream::Raw(
+                    BuildTimeRenderingStreamRaw {
+                        value: " ".to_string(),
+                    }
+                ),
+                BuildTimeRenderingStream::Attribute(
+                    BuildTimeRenderingStreamAttribute {
+                        value: "'item-' + items.length".to_string(),
+                        name: "class".to_string(),
+                        default_value: None,
+                        html: false,
+                        expression: true,
+                    }
+                ),
+            ],
+            templates: HashMap::new(),
+        };
+        let state = json!({
+            "items": ["a", "b", "c"],
+            "done": 1
+        });
+        let mut server_handler = TestServerHandler::new();
+        handle_btr(protocol, state, &mut server_handler);
+        assert_eq!(server_handler.get_output(), "2 none class=\"item-3\"");
+    }
+
+    #[test]
+    fn test_handle_btr_signal_filters() {
+        let signal = |value: &str, filters: &[&str]| {
+            BuildTimeRenderingStream::Signal(
+                BuildTimeRenderingStreamSignal {
+                    value: value.to_string(),
+                    default_value: Some("n/a".to_string()),
+                    html: false,
+                    expression: false,
+                    filters: filters.iter().map(|filter| filter.to_string()).collect(),
+                }
+            )
+        };
+        let protocol = BuildTimeRenderingProtocol {
+            streams: vec![
+                signal("price", &["currency:USD"]),
+                signal("title", &["truncate:5"]),
+                signal("price", &["date"]),
+                signal("tags", &["json"]),
+                signal("title", &["number"]),
+            ],
+            templates: HashMap::new(),
+        };
+        let state = json!({
+            "price": 1234.5,
+            "title": "<b>Hello</b> world",
+            "tags": ["a<b"]
+        });
+        let mut server_handler = TestServerHandler::new();
+        handle_btr(protocol, state, &mut server_handler);
+        assert_eq!(server_handler.get_output(), "$1,234.50&lt;b&gt;He…1234.5[\"a&lt;b\"]n/a");
+    }
+
     #[test]
     fn test_handle_btr_attribute() {
         let protocol = BuildTimeRenderingProtocol {
@@ -187,6 +936,8 @@ mod tests {
                         value: "fruit".to_string(),
                         name: "href".to_string(),
                         default_value: None,
+                        html: false,
+                        expression: false,
                     }
                 )
             ],
@@ -197,7 +948,7 @@ mod tests {
         });
         let mut server_handler = TestServerHandler::new();
         handle_btr(protocol, state, &mut server_handler);
-        assert_eq!(server_handler.get_output(), "href=apple");
+        assert_eq!(server_handler.get_output(), "href=\"apple\"");
     }
     
     #[test]
@@ -209,6 +960,8 @@ mod tests {
                         value: "fruit".to_string(),
                         name: "href".to_string(),
                         default_value: Some("pineapple".to_string()),
+                        html: false,
+                        expression: false,
                     }
                 )
             ],
@@ -219,7 +972,7 @@ mod tests {
         });
         let mut server_handler = TestServerHandler::new();
         handle_btr(protocol, state, &mut server_handler);
-        assert_eq!(server_handler.get_output(), "href=pineapple");
+        assert_eq!(server_handler.get_output(), "href=\"pineapple\"");
     }
 
     #[test]
@@ -229,6 +982,10 @@ mod tests {
                 BuildTimeRenderingStream::When(
                     BuildTimeRenderingStreamWhen {
                         value: "a > 5".to_string(),
+                        mode: BuildTimeRenderingWhenMode::Style,
+                        style: None,
+                        streams: Vec::new(),
+                        else_streams: Vec::new(),
                     }
                 ),
             ],
@@ -249,6 +1006,10 @@ mod tests {
                 BuildTimeRenderingStream::When(
                     BuildTimeRenderingStreamWhen {
                         value: "a > 10".to_string(),
+                        mode: BuildTimeRenderingWhenMode::Style,
+                        style: None,
+                        streams: Vec::new(),
+                        else_streams: Vec::new(),
                     }
                 ),
             ],
@@ -262,6 +1023,96 @@ mod tests {
         assert_eq!(server_handler.get_output(), "style=\"display: none\"");
     }
 
+    #[test]
+    fn test_handle_btr_when_invalid() {
+        let protocol = BuildTimeRenderingProtocol {
+            streams: vec![
+                BuildTimeRenderingStream::When(
+                    BuildTimeRenderingStreamWhen {
+                        value: "a >".to_string(),
+                        mode: BuildTimeRenderingWhenMode::Style,
+                        style: None,
+                        streams: Vec::new(),
+                        else_streams: Vec::new(),
+                    }
+                ),
+            ],
+            templates: HashMap::new(),
+        };
+        let state = json!({
+            "a": 10
+        });
+        let mut server_handler = TestServerHandler::new();
+        handle_btr(protocol, state, &mut server_handler);
+        assert_eq!(server_handler.get_output(), "style=\"display: none\"");
+    }
+
+    #[test]
+    fn test_handle_compiled_btr() {
+        let protocol = BuildTimeRenderingProtocol {
+            streams: vec![
+                BuildTimeRenderingStream::Signal(
+                    BuildTimeRenderingStreamSignal {
+                        value: "a".to_string(),
+                        default_value: None,
+                        html: false,
+                        expression: false,
+                        filters: Vec::new(),
+                    }
+                ),
+                BuildTimeRenderingStream::When(
+                    BuildTimeRenderingStreamWhen {
+                        value: "a > 5".to_string(),
+                        mode: BuildTimeRenderingWhenMode::Style,
+                        style: None,
+                        streams: Vec::new(),
+                        else_streams: Vec::new(),
+                    }
+                ),
+            ],
+            templates: HashMap::new(),
+        };
+        let protocol = CompiledProtocol::try_from(protocol).unwrap();
+        for (state, expected) in [(json!({ "a": 10 }), "10"), (json!({ "a": 1 }), "1style=\"display: none\"")] {
+            let mut server_handler = TestServerHandler::new();
+            handle_compiled_btr(&protocol, &state, &mut server_handler);
+            assert_eq!(server_handler.get_output(), expected);
+        }
+    }
+
+    #[test]
+    fn test_handle_compiled_btr_functions() {
+        let protocol = BuildTimeRenderingProtocol {
+            streams: vec![
+                BuildTimeRenderingStream::Signal(
+                    BuildTimeRenderingStreamSignal {
+                        value: "upper(name) + ' ' + shout(name)".to_string(),
+                        default_value: None,
+                        html: false,
+                        expression: true,
+                        filters: Vec::new(),
+                    }
+                ),
+                BuildTimeRenderingStream::When(
+                    BuildTimeRenderingStreamWhen {
+                        value: "len(name) > 3".to_string(),
+                        mode: BuildTimeRenderingWhenMode::Style,
+                        style: None,
+                        streams: Vec::new(),
+                        else_streams: Vec::new(),
+                    }
+                ),
+            ],
+            templates: HashMap::new(),
+        };
+        let mut protocol = CompiledProtocol::try_from(protocol).unwrap();
+        protocol.functions.register("shout", |args| Value::String(format!("{}!", to_text(args[0]))));
+
+        let mut server_handler = TestServerHandler::new();
+        handle_compiled_btr(&protocol, &json!({ "name": "Ada" }), &mut server_handler);
+        assert_eq!(server_handler.get_output(), "ADA Ada!style=\"display: none\"");
+    }
+
     #[test]
     fn test_handle_btr_repeat() {
         let protocol = BuildTimeRenderingProtocol {
@@ -270,6 +1121,8 @@ mod tests {
                     BuildTimeRenderingStreamRepeat {
                         template: "item".to_string(),
                         value: "items".to_string(),
+                        html: false,
+                        empty: Vec::new(),
                     }
                 ),
             ],
@@ -280,6 +1133,9 @@ mod tests {
                     BuildTimeRenderingTemplate {
                         template: "<div></div>".to_string(),
                         style: None,
+                        streams: Vec::new(),
+                        shadow_root_mode: BuildTimeRenderingShadowRootMode::Open,
+                        delegates_focus: false,
                     },
                 );
                 map
@@ -306,6 +1162,8 @@ mod tests {
                     BuildTimeRenderingStreamRepeat {
                         template: "item".to_string(),
                         value: "items".to_string(),
+                        html: false,
+                        empty: Vec::new(),
                     }
                 ),
             ],
@@ -316,6 +1174,9 @@ mod tests {
                     BuildTimeRenderingTemplate {
                         template: "<div></div>".to_string(),
                         style: None,
+                        streams: Vec::new(),
+                        shadow_root_mode: BuildTimeRenderingShadowRootMode::Open,
+                        delegates_focus: false,
                     },
                 );
                 map
@@ -347,6 +1208,377 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_handle_btr_repeat_escaped() {
+        let protocol = BuildTimeRenderingProtocol {
+            streams: vec![
+                BuildTimeRenderingStream::Repeat(
+                    BuildTimeRenderingStreamRepeat {
+                        template: "item".to_string(),
+                        value: "items".to_string(),
+                        html: false,
+                        empty: Vec::new(),
+                    }
+                ),
+            ],
+            templates: {
+                let mut map = HashMap::new();
+                map.insert(
+                    "item".to_string(),
+                    BuildTimeRenderingTemplate {
+                        template: "<slot></slot>".to_string(),
+                        style: None,
+                        streams: Vec::new(),
+                        shadow_root_mode: BuildTimeRenderingShadowRootMode::Open,
+                        delegates_focus: false,
+                    },
+                );
+                map
+            },
+        };
+        let state = json!({
+            "items": [
+                "<img src=x>",
+                { "\"><b": "</span>" }
+            ]
+        });
+        let mut server_handler = TestServerHandler::new();
+        handle_btr(protocol, state, &mut server_handler);
+        assert_eq!(
+            server_handler.get_output(),
+            "<item><template shadowrootmode=\"open\"><slot></slot></template>&lt;img src=x&gt;</item>\
+            <item><template shadowrootmode=\"open\"><slot></slot></template><span slot=\"&quot;&gt;&lt;b\">&lt;/span&gt;</span></item>"
+        );
+    }
+
+    #[test]
+    fn test_handle_btr_nested_repeat() {
+        let protocol: BuildTimeRenderingProtocol = serde_json::from_value(json!({
+            "streams": [
+                { "type": "repeat", "value": "lists", "template": "todo-list" }
+            ],
+            "templates": {
+                "todo-list": {
+                    "streams": [
+                        { "type": "raw", "value": "<h2>" },
+                        { "type": "signal", "value": "name" },
+                        { "type": "raw", "value": "</h2>" },
+                        { "type": "repeat", "value": "item.items", "template": "todo-item" }
+                    ]
+                },
+                "todo-item": {
+                    "style": "li { color: red; }",
+                    "streams": [
+                        { "type": "raw", "value": "<li " },
+                        { "type": "attribute", "name": "data-list", "value": "$parent.index" },
+                        { "type": "raw", "value": " " },
+                        { "type": "when", "value": "item.done" },
+                        { "type": "raw", "value": ">" },
+                        { "type": "signal", "value": "index + 1 + '. ' + item.title + ' (' + owner + ')'", "expression": true },
+                        { "type": "raw", "value": "</li>" }
+                    ]
+                }
+            }
+        }))
+        .unwrap();
+        let state = json!({
+            "owner": "Ada",
+            "lists": [
+                { "name": "Home", "items": [{ "title": "Dishes", "done": true }, { "title": "<Laundry>", "done": false }] },
+                { "name": "Work", "items": [] }
+            ]
+        });
+        let mut server_handler = TestServerHandler::new();
+        handle_btr(protocol, state, &mut server_handler);
+        assert_eq!(
+            server_handler.get_output(),
+            concat!(
+                "<todo-list><template shadowrootmode=\"open\"><h2>Home</h2>",
+                "<todo-item><template shadowrootmode=\"open\"><style>li { color: red; }</style>",
+                "<li data-list=\"0\" >1. Dishes (Ada)</li></template></todo-item>",
+                "<todo-item><template shadowrootmode=\"open\"><style>li { color: red; }</style>",
+                "<li data-list=\"0\" style=\"display: none\">2. &lt;Laundry&gt; (Ada)</li></template></todo-item>",
+                "</template></todo-list>",
+                "<todo-list><template shadowrootmode=\"open\"><h2>Work</h2></template></todo-list>",
+            )
+        );
+    }
+
+    #[test]
+    fn test_handle_btr_repeat_loop_metadata() {
+        let protocol: BuildTimeRenderingProtocol = serde_json::from_value(json!({
+            "streams": [
+                { "type": "repeat", "value": "tags", "template": "tag-item" }
+            ],
+            "templates": {
+                "tag-item": {
+                    "streams": [
+                        { "type": "signal", "value": "index + 1 + '/' + length + ':' + item", "expression": true },
+                        { "type": "raw", "value": "<i " },
+                        { "type": "attribute", "name": "class", "value": "even ? 'even' : 'odd'", "expression": true },
+                        { "type": "raw", "value": " " },
+                        { "type": "attribute", "name": "data-first", "value": "first" },
+                        { "type": "raw", "value": " " },
+                        { "type": "when", "value": "!last" },
+                        { "type": "raw", "value": ">,</i>" }
+                    ]
+                }
+            }
+        }))
+        .unwrap();
+        let state = json!({ "tags": ["a", "b", "c"] });
+        let mut server_handler = TestServerHandler::new();
+        handle_btr(protocol, state, &mut server_handler);
+
+        let item = |content: &str| format!("<tag-item><template shadowrootmode=\"open\">{}</template></tag-item>", content);
+        assert_eq!(
+            server_handler.get_output(),
+            [
+                item("1/3:a<i class=\"even\" data-first=\"true\" >,</i>"),
+                item("2/3:b<i class=\"odd\" data-first=\"false\" >,</i>"),
+                item("3/3:c<i class=\"even\" data-first=\"false\" style=\"display: none\">,</i>"),
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_handle_btr_when_else_and_repeat_empty() {
+        let protocol: BuildTimeRenderingProtocol = serde_json::from_value(json!({
+            "streams": [
+                {
+                    "type": "when",
+                    "value": "items.length > 1",
+                    "streams": [{ "type": "raw", "value": "<p>Many</p>" }],
+                    "else": [{ "type": "raw", "value": "<p>Few</p>" }]
+                },
+                {
+                    "type": "repeat",
+                    "value": "items",
+                    "template": "app-item",
+                    "empty": [{ "type": "raw", "value": "<p>No items to show</p>" }]
+                }
+            ],
+            "templates": {
+                "app-item": { "template": "<slot></slot>" }
+            }
+        }))
+        .unwrap();
+        let protocol = CompiledProtocol::try_from(protocol).unwrap();
+
+        let test_cases = [
+            (json!({ "items": [] }), "<p>Few</p><p>No items to show</p>"),
+            (json!({}), "<p>Few</p><p>No items to show</p>"),
+            (
+                json!({ "items": ["a", "b"] }),
+                concat!(
+                    "<p>Many</p>",
+                    "<app-item><template shadowrootmode=\"open\"><slot></slot></template>a</app-item>",
+                    "<app-item><template shadowrootmode=\"open\"><slot></slot></template>b</app-item>",
+                ),
+            ),
+        ];
+        for (state, expected) in test_cases {
+            let mut server_handler = TestServerHandler::new();
+            handle_compiled_btr(&protocol, &state, &mut server_handler);
+            assert_eq!(server_handler.get_output(), expected);
+        }
+    }
+
+    #[test]
+    fn test_handle_btr_when_modes() {
+        let protocol: BuildTimeRenderingProtocol = serde_json::from_value(json!({
+            "streams": [
+                { "type": "raw", "value": "<p " },
+                { "type": "when", "value": "show", "mode": "merge", "style": "color: red;" },
+                { "type": "raw", "value": "></p><p " },
+                { "type": "when", "value": "show", "mode": "hidden" },
+                { "type": "raw", "value": "></p>" },
+                { "type": "when", "value": "show", "mode": "structural" },
+                { "type": "raw", "value": "<ul>" },
+                { "type": "when", "value": "item", "mode": "structural" },
+                { "type": "signal", "value": "item" },
+                { "type": "whenEnd" },
+                { "type": "raw", "value": "</ul>" },
+                { "type": "whenEnd" },
+                { "type": "raw", "value": "!" }
+            ],
+            "templates": {}
+        }))
+        .unwrap();
+        let protocol = CompiledProtocol::try_from(protocol).unwrap();
+
+        let test_cases = [
+            (json!({ "show": true, "item": "a" }), "<p style=\"color: red\"></p><p ></p><ul>a</ul>!"),
+            (json!({ "show": true }), "<p style=\"color: red\"></p><p ></p><ul></ul>!"),
+            (json!({ "show": false, "item": "a" }), "<p style=\"color: red; display: none\"></p><p hidden></p>!"),
+        ];
+        for (state, expected) in test_cases {
+            let mut server_handler = TestServerHandler::new();
+            handle_compiled_btr(&protocol, &state, &mut server_handler);
+            assert_eq!(server_handler.get_output(), expected);
+        }
+
+        let protocol: BuildTimeRenderingProtocol = serde_json::from_value(json!({
+            "streams": [{ "type": "when", "value": "show", "mode": "merge", "style": "font-family: \"A\"" }],
+            "templates": {}
+        }))
+        .unwrap();
+        let protocol = CompiledProtocol::try_from(protocol).unwrap();
+        let test_cases = [
+            (json!({ "show": true }), "style=\"font-family: &quot;A&quot;\""),
+            (json!({ "show": false }), "style=\"font-family: &quot;A&quot;; display: none\""),
+        ];
+        for (state, expected) in test_cases {
+            let mut server_handler = TestServerHandler::new();
+            handle_compiled_btr(&protocol, &state, &mut server_handler);
+            assert_eq!(server_handler.get_output(), expected);
+        }
+    }
+
+    #[test]
+    fn test_handle_btr_shadow_root_options() {
+        let protocol: BuildTimeRenderingProtocol = serde_json::from_value(json!({
+            "streams": [
+                { "type": "repeat", "value": "items", "template": "x-closed" },
+                { "type": "repeat", "value": "items", "template": "x-light" },
+                { "type": "repeat", "value": "items", "template": "x-dynamic" }
+            ],
+            "templates": {
+                "x-closed": {
+                    "style": "b { color: red; }",
+                    "template": "<b><slot name=\"name\"></slot></b>",
+                    "shadowRootMode": "closed",
+                    "delegatesFocus": true
+                },
+                "x-light": {
+                    "style": ":host { display: block; } :host(.done) i { color: blue; }",
+                    "template": "<i><slot name=\"name\">?</slot></i>",
+                    "shadowRootMode": "none"
+                },
+                "x-dynamic": {
+                    "shadowRootMode": "none",
+                    "streams": [{ "type": "signal", "value": "name" }]
+                }
+            }
+        }))
+        .unwrap();
+        let protocol = CompiledProtocol::try_from(protocol).unwrap();
+
+        let state = json!({ "items": [{ "name": "a" }, { "name": "b" }] });
+        let mut server_handler = TestServerHandler::new();
+        handle_compiled_btr(&protocol, &state, &mut server_handler);
+        assert_eq!(
+            server_handler.get_output(),
+            concat!(
+                "<x-closed><template shadowrootmode=\"closed\" shadowrootdelegatesfocus><style>b { color: red; }</style>",
+                "<b><slot name=\"name\"></slot></b></template><span slot=\"name\">a</span></x-closed>",
+                "<x-closed><template shadowrootmode=\"closed\" shadowrootdelegatesfocus><style>b { color: red; }</style>",
+                "<b><slot name=\"name\"></slot></b></template><span slot=\"name\">b</span></x-closed>",
+                "<style>@scope (x-light) { :scope { display: block; } :scope:is(.done) i { color: blue; } }</style>",
+                "<x-light><i>a</i></x-light><x-light><i>b</i></x-light>",
+                "<x-dynamic>a</x-dynamic><x-dynamic>b</x-dynamic>"
+            )
+        );
+    }
+
+    #[test]
+    fn test_handle_btr_light_dom_slots() {
+        let protocol: BuildTimeRenderingProtocol = serde_json::from_value(json!({
+            "streams": [{ "type": "repeat", "value": "items", "template": "x-light" }],
+            "templates": {
+                "x-light": {
+                    "template": concat!(
+                        "<i title=\"a > b\"><slot class=\"x name=y\" name='name'>?</slot></i><slot>-</slot>",
+                        "<slotted></slotted><slot name=\"a\"><slot name=\"b\">!</slot></slot>"
+                    ),
+                    "shadowRootMode": "none"
+                }
+            }
+        }))
+        .unwrap();
+        let protocol = CompiledProtocol::try_from(protocol).unwrap();
+
+        let state = json!({ "items": [{ "name": "a & b" }, { "name": null }, "c", ["x", 2], { "b": "B" }] });
+        let mut server_handler = TestServerHandler::new();
+        handle_compiled_btr(&protocol, &state, &mut server_handler);
+        assert_eq!(
+            server_handler.get_output(),
+            concat!(
+                "<x-light><i title=\"a > b\">a &amp; b</i>-<slotted></slotted>!</x-light>",
+                "<x-light><i title=\"a > b\">?</i>-<slotted></slotted>!</x-light>",
+                "<x-light><i title=\"a > b\">?</i>c<slotted></slotted>!</x-light>",
+                "<x-light><i title=\"a > b\">?</i>x,2<slotted></slotted>!</x-light>",
+                "<x-light><i title=\"a > b\">?</i>-<slotted></slotted>B</x-light>"
+            )
+        );
+    }
+
+    #[test]
+    fn test_handle_btr_render_policy() {
+        let protocol: BuildTimeRenderingProtocol = serde_json::from_value(json!({
+            "streams": [
+                { "type": "signal", "value": "title", "defaultValue": "Untitled" },
+                { "type": "attribute", "name": "class", "value": "theme" },
+                { "type": "repeat", "value": "count", "template": "x-item", "empty": [{ "type": "raw", "value": "-" }] },
+                { "type": "repeat", "value": "items", "template": "x-missing" },
+                { "type": "repeat", "value": "none", "template": "x-item" },
+                { "type": "signal", "value": "null" }
+            ],
+            "templates": { "x-item": {} }
+        }))
+        .unwrap();
+        let protocol = CompiledProtocol::try_from(protocol).unwrap();
+        let state = json!({ "count": 2, "items": ["a"], "null": null });
+        let warning = |kind, value: &str| RenderWarning {
+            kind,
+            value: value.to_string(),
+        };
+
+        let mut server_handler = TestServerHandler::new();
+        let options = RenderOptions::default();
+        let warnings = handle_compiled_btr_with_options(&protocol, &state, &mut server_handler, &options);
+        assert_eq!(warnings, Ok(Vec::new()));
+        let output = server_handler.get_output();
+        assert_eq!(output, "Untitled-<x-missing><template shadowrootmode=\"open\"></template>a</x-missing>");
+
+        let mut server_handler = TestServerHandler::new();
+        let options = RenderOptions {
+            policy: RenderPolicy::Warn,
+        };
+        let warnings = handle_compiled_btr_with_options(&protocol, &state, &mut server_handler, &options);
+        assert_eq!(server_handler.get_output(), output);
+        assert_eq!(
+            warnings,
+            Ok(vec![
+                warning(RenderWarningKind::MissingValue, "title"),
+                warning(RenderWarningKind::MissingValue, "theme"),
+                warning(RenderWarningKind::NotAnArray, "count"),
+                warning(RenderWarningKind::UnknownTemplate, "x-missing"),
+                warning(RenderWarningKind::MissingValue, "none"),
+                warning(RenderWarningKind::MissingValue, "null"),
+            ])
+        );
+
+        let mut server_handler = TestServerHandler::new();
+        let options = RenderOptions {
+            policy: RenderPolicy::Strict,
+        };
+        let error = handle_compiled_btr_with_options(&protocol, &state, &mut server_handler, &options).unwrap_err();
+        assert_eq!(error, RenderError::Render(warning(RenderWarningKind::MissingValue, "title")));
+        assert_eq!(error.to_string(), "Missing value 'title'");
+        assert_eq!(server_handler.get_output(), "");
+
+        let invalid: BuildTimeRenderingProtocol = serde_json::from_value(json!({
+            "streams": [{ "type": "when", "value": "a &&" }],
+            "templates": {}
+        }))
+        .unwrap();
+        let mut server_handler = TestServerHandler::new();
+        let result = handle_btr_with_options(invalid, Value::Null, &mut server_handler, &options);
+        assert!(matches!(result, Err(RenderError::Compile(_))));
+    }
+
     #[test]
     fn test_handle_btr_repeat_with_style() {
         let protocol = BuildTimeRenderingProtocol {
@@ -355,6 +1587,8 @@ mod tests {
                     BuildTimeRenderingStreamRepeat {
                         template: "item".to_string(),
                         value: "items".to_string(),
+                        html: false,
+                        empty: Vec::new(),
                     }
                 ),
             ],
@@ -365,6 +1599,9 @@ mod tests {
                     BuildTimeRenderingTemplate {
                         template: "<div></div>".to_string(),
                         style: Some(":host\\{color:red;\\}".to_string()),
+                        streams: Vec::new(),
+                        shadow_root_mode: BuildTimeRenderingShadowRootMode::Open,
+                        delegates_focus: false,
                     },
                 );
                 map
@@ -377,4 +1614,37 @@ mod tests {
         handle_btr(protocol, state, &mut server_handler);
         assert_eq!(server_handler.get_output(), "<item><template shadowrootmode=\"open\"><style>:host\\{color:red;\\}</style><div></div></template>item</item>");
     }
+
+    #[test]
+    fn test_handle_btr_repeat_linked_styles() {
+        let protocol: BuildTimeRenderingProtocol = serde_json::from_value(json!({
+            "streams": [{ "type": "repeat", "value": "items", "template": "x-item" }],
+            "templates": {
+                "x-item": {
+                    "style": ":host { display: block; padding: 4px 8px; } div { color: red; font: 14px sans-serif; }",
+                    "template": "<div><slot name=\"name\"></slot></div>"
+                }
+            }
+        }))
+        .unwrap();
+        let mut protocol = CompiledProtocol::try_from(protocol).unwrap();
+        let items: Vec<Value> = (0..100).map(|i| json!({ "name": i })).collect();
+        let state = json!({ "items": items });
+
+        let mut inlined = TestServerHandler::new();
+        handle_compiled_btr(&protocol, &state, &mut inlined);
+
+        protocol.stylesheets = Some("/styles/".to_string());
+        let mut linked = TestServerHandler::new();
+        handle_compiled_btr(&protocol, &state, &mut linked);
+
+        let linked = linked.get_output();
+        assert!(linked.starts_with(
+            "<x-item><template shadowrootmode=\"open\"><link rel=\"stylesheet\" href=\"/styles/x-item.css\"><div>"
+        ));
+        assert!(!linked.contains("<style>"));
+        assert!(linked.len() < inlined.get_output().len());
+        assert_eq!(protocol.stylesheet("x-item"), Some(":host { display: block; padding: 4px 8px; } div { color: red; font: 14px sans-serif; }"));
+        assert_eq!(protocol.stylesheet("missing"), None);
+    }
 }
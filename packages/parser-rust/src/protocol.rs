@@ -1,9 +1,14 @@
+use crate::compiled::{CompileError, CompiledProtocol};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::error::Category;
+use serde_json::Value;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{self, BufReader};
 use std::collections::HashMap;
+use std::fmt;
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "type", rename_all = "camelCase")]
 pub enum BuildTimeRenderingStream {
     Attribute(BuildTimeRenderingStreamAttribute),
@@ -11,56 +16,258 @@ pub enum BuildTimeRenderingStream {
     Repeat(BuildTimeRenderingStreamRepeat),
     Signal(BuildTimeRenderingStreamSignal),
     When(BuildTimeRenderingStreamWhen),
+    // Closes the region opened by a structural when.
+    WhenEnd,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub struct BuildTimeRenderingStreamAttribute {
     pub value: String,
     pub name: String,
     #[serde(rename = "defaultValue")]
     pub default_value: Option<String>,
+    // Writes the value as-is instead of escaping it, only use for trusted HTML.
+    #[serde(default)]
+    pub html: bool,
+    // The value is an expression to evaluate rather than a dotted path.
+    #[serde(default)]
+    pub expression: bool,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub struct BuildTimeRenderingStreamRaw {
     pub value: String,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub struct BuildTimeRenderingStreamRepeat {
     pub value: String,
     pub template: String,
+    // Writes the item values as-is instead of escaping them, only use for trusted HTML.
+    #[serde(default)]
+    pub html: bool,
+    // Rendered instead of the items when the array is missing or empty.
+    #[serde(default)]
+    pub empty: Vec<BuildTimeRenderingStream>,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub struct BuildTimeRenderingStreamSignal {
     pub value: String,
     #[serde(rename = "defaultValue")]
     pub default_value: Option<String>,
+    // Writes the value as-is instead of escaping it, only use for trusted HTML.
+    #[serde(default)]
+    pub html: bool,
+    // The value is an expression to evaluate rather than a dotted path.
+    #[serde(default)]
+    pub expression: bool,
+    // Filters applied to the value in order, such as `number:2` or `currency:USD`.
+    #[serde(default)]
+    pub filters: Vec<String>,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub struct BuildTimeRenderingStreamWhen {
     pub value: String,
+    #[serde(default)]
+    pub mode: BuildTimeRenderingWhenMode,
+    // The existing style of the element, written as-is and merged with `display: none` in the `merge` mode.
+    pub style: Option<String>,
+    // With either block the when is structural: only the block that applies is rendered rather than
+    // hiding the element with a style.
+    #[serde(default)]
+    pub streams: Vec<BuildTimeRenderingStream>,
+    #[serde(default, rename = "else")]
+    pub else_streams: Vec<BuildTimeRenderingStream>,
+}
+
+// How a when hides its element when the condition is false.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum BuildTimeRenderingWhenMode {
+    // Writes `style="display: none"`.
+    #[default]
+    Style,
+    // Writes the `style` of the when followed by `display: none`, so the element keeps its own style.
+    Merge,
+    // Writes the `hidden` attribute.
+    Hidden,
+    // The streams up to the matching `whenEnd` are only rendered when the condition is true.
+    Structural,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub struct BuildTimeRenderingTemplate {
     pub style: Option<String>,
+    // The static HTML of the template, left out for templates rendered from `streams`.
+    #[serde(default)]
     pub template: String,
+    // Renders the template from these streams against each repeated item instead of the static
+    // `template`, so it can bind the item and contain nested repeats.
+    #[serde(default)]
+    pub streams: Vec<BuildTimeRenderingStream>,
+    // How the template is attached to each item, `none` renders it in the light DOM where its style is
+    // written once per page rather than once per item, and the item is written in place of its slots.
+    #[serde(default, rename = "shadowRootMode")]
+    pub shadow_root_mode: BuildTimeRenderingShadowRootMode,
+    #[serde(default, rename = "delegatesFocus")]
+    pub delegates_focus: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum BuildTimeRenderingShadowRootMode {
+    #[default]
+    Open,
+    Closed,
+    None,
 }
 
 pub type BuildTimeRenderingStreamTemplateRecords = HashMap<String, BuildTimeRenderingTemplate>;
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub struct BuildTimeRenderingProtocol {
     pub streams: Vec<BuildTimeRenderingStream>,
     pub templates: BuildTimeRenderingStreamTemplateRecords,
 }
 
-pub fn load_protocol_from_file(file_path: &str) -> Result<BuildTimeRenderingProtocol, serde_json::Error> {
-    let file = File::open(file_path).unwrap();
+// The version of the protocol format, part of the `$id` of its JSON Schema. Bump it on breaking changes.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// The JSON Schema of the protocol, generated from these types so other tooling can validate protocols
+// against the same definition the loader uses. It is published as `protocol-js/schema.json`.
+pub fn protocol_schema() -> Value {
+    let mut schema = schemars::schema_for!(BuildTimeRenderingProtocol);
+    schema.insert("$id".to_string(), Value::String(format!("urn:btjs:protocol:v{}", PROTOCOL_VERSION)));
+    schema.to_value()
+}
+
+// Why a protocol file could not be loaded, each variant carries the path of the file.
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io { path: String, error: io::Error },
+    // The file is not valid JSON or does not match the protocol, `line` and `column` start at 1.
+    Parse { path: String, line: usize, column: usize, error: serde_json::Error },
+    // The protocol has a `when` expression or path that cannot be compiled.
+    Validation { path: String, error: CompileError },
+}
+
+impl ProtocolError {
+    pub fn path(&self) -> &str {
+        match self {
+            ProtocolError::Io { path, .. } | ProtocolError::Parse { path, .. } | ProtocolError::Validation { path, .. } => path,
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Io { path, error } => write!(f, "Could not read protocol '{}': {}", path, error),
+            // serde_json already reports the line and column.
+            ProtocolError::Parse { path, error, .. } => write!(f, "Invalid protocol '{}': {}", path, error),
+            ProtocolError::Validation { path, error } => write!(f, "Invalid protocol '{}': {}", path, error),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::Io { error, .. } => Some(error),
+            ProtocolError::Parse { error, .. } => Some(error),
+            ProtocolError::Validation { error, .. } => Some(error),
+        }
+    }
+}
+
+pub fn load_protocol_from_file(file_path: &str) -> Result<BuildTimeRenderingProtocol, ProtocolError> {
+    let file = File::open(file_path).map_err(|error| ProtocolError::Io {
+        path: file_path.to_string(),
+        error,
+    })?;
     let reader = BufReader::new(file);
-    let protocol = serde_json::from_reader(reader)?;
-    Ok(protocol)
+    serde_json::from_reader(reader).map_err(|error| match error.classify() {
+        Category::Io => ProtocolError::Io {
+            path: file_path.to_string(),
+            error: error.into(),
+        },
+        _ => ProtocolError::Parse {
+            path: file_path.to_string(),
+            line: error.line(),
+            column: error.column(),
+            error,
+        },
+    })
+}
+
+// Loads and compiles a protocol, failing on invalid expressions and paths as well.
+pub fn load_compiled_protocol_from_file(file_path: &str) -> Result<CompiledProtocol, ProtocolError> {
+    let protocol = load_protocol_from_file(file_path)?;
+    CompiledProtocol::try_from(protocol).map_err(|error| ProtocolError::Validation {
+        path: file_path.to_string(),
+        error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiled::CompileErrorKind;
+    use std::fs;
+
+    fn write_protocol(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("btjs-{}-{}.json", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_protocol_schema() {
+        let schema = protocol_schema();
+        assert_eq!(schema["$id"], "urn:btjs:protocol:v1");
+        assert_eq!(schema["title"], "BuildTimeRenderingProtocol");
+        assert_eq!(schema["required"], serde_json::json!(["streams", "templates"]));
+
+        let template = &schema["$defs"]["BuildTimeRenderingTemplate"];
+        assert_eq!(template["required"], serde_json::Value::Null);
+        assert_eq!(template["properties"]["template"]["default"], "");
+        assert_eq!(template["properties"]["shadowRootMode"]["$ref"], "#/$defs/BuildTimeRenderingShadowRootMode");
+    }
+
+    // The published schema is generated, run the tests with `BTJS_UPDATE_SCHEMA=1` to update it.
+    #[test]
+    fn test_published_protocol_schema_is_current() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../protocol-js/schema.json");
+        let schema = serde_json::to_string_pretty(&protocol_schema()).unwrap() + "\n";
+        if std::env::var_os("BTJS_UPDATE_SCHEMA").is_some() {
+            fs::write(&path, &schema).unwrap();
+        }
+        let published = fs::read_to_string(&path).unwrap_or_default().replace("\r\n", "\n");
+        assert!(published == schema, "{} is out of date, run the tests with BTJS_UPDATE_SCHEMA=1", path.display());
+    }
+
+    #[test]
+    fn test_load_protocol_errors() {
+        let missing = std::env::temp_dir().join("btjs-missing.json").to_string_lossy().into_owned();
+        let error = load_protocol_from_file(&missing).err().unwrap();
+        assert!(matches!(&error, ProtocolError::Io { error, .. } if error.kind() == io::ErrorKind::NotFound));
+        assert_eq!(error.path(), missing);
+
+        let path = write_protocol("parse", "{\n  \"streams\": [\n    { \"type\": \"raw\" \"value\": \"a\" }\n  ]\n}");
+        let error = load_protocol_from_file(&path).err().unwrap();
+        assert!(matches!(error, ProtocolError::Parse { line: 3, column: 21, .. }), "{:?}", error);
+        assert!(error.to_string().starts_with(&format!("Invalid protocol '{}': expected `,` or `}}`", path)));
+
+        let path = write_protocol("validation", r#"{ "streams": [{ "type": "when", "value": "a &&" }], "templates": {} }"#);
+        let error = load_compiled_protocol_from_file(&path).err().unwrap();
+        assert!(matches!(
+            error,
+            ProtocolError::Validation { error: CompileError { stream_index: 0, kind: CompileErrorKind::Expression(_), .. }, .. }
+        ));
+
+        let path = write_protocol("valid", r#"{ "streams": [{ "type": "raw", "value": "a" }], "templates": {} }"#);
+        assert_eq!(load_compiled_protocol_from_file(&path).unwrap().streams.len(), 1);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,137 @@
+use crate::values::*;
+use serde_json::Value;
+use std::borrow::Cow;
+
+// A level of the scope chain, the state at the root and one level for each repeated item.
+#[derive(Clone, Copy, Debug)]
+pub enum ScopeFrame<'a> {
+    Root(&'a Value),
+    Item { item: &'a Value, index: usize, length: usize },
+}
+
+// The values paths are resolved against while rendering, the last frame is the current scope. Within a
+// repeat `item` is the current item and `$parent` the enclosing scope, `$index`, `$first`, `$last`,
+// `$even`, `$odd` and `$length` describe the position of the item. The names without `$` do too, unless
+// the item has a field of that name, such as a person's `first` name. Other paths are looked up on the
+// item first and then in the enclosing scopes.
+#[derive(Clone, Copy, Debug)]
+pub struct Scope<'s, 'a> {
+    frames: &'s [ScopeFrame<'a>],
+}
+
+impl<'s, 'a> Scope<'s, 'a> {
+    pub fn new(frames: &'s [ScopeFrame<'a>]) -> Self {
+        Scope { frames }
+    }
+
+    pub fn lookup(&self, path: &Path) -> Option<Cow<'a, Value>> {
+        self.lookup_segments(path.segments())
+    }
+
+    fn lookup_segments(&self, segments: &[PathSegment]) -> Option<Cow<'a, Value>> {
+        let (current, parents) = self.frames.split_last()?;
+        let parent = Scope { frames: parents };
+        let (item, index, length) = match *current {
+            ScopeFrame::Root(state) => return lookup_segments(segments, state),
+            ScopeFrame::Item { item, index, length } => (item, index, length),
+        };
+
+        let lookup_item = || lookup_segments(segments, item).or_else(|| parent.lookup_segments(segments));
+        match segments.split_first() {
+            Some((PathSegment::Key(key), rest)) if key == "item" => lookup_segments(rest, item),
+            Some((PathSegment::Key(key), rest)) if key == "$parent" => parent.lookup_segments(rest),
+            Some((PathSegment::Key(key), [])) => {
+                let position = match key.strip_prefix('$') {
+                    Some(name) => loop_value(name, index, length),
+                    None if item.get(key.as_str()).is_some() => None,
+                    None => loop_value(key, index, length),
+                };
+                position.map(Cow::Owned).or_else(lookup_item)
+            }
+            _ => lookup_item(),
+        }
+    }
+}
+
+// The position of a repeated item, `even` and `odd` count from an `index` of 0.
+pub(crate) fn loop_value(key: &str, index: usize, length: usize) -> Option<Value> {
+    match key {
+        "index" => Some(Value::from(index)),
+        "first" => Some(Value::Bool(index == 0)),
+        "last" => Some(Value::Bool(index + 1 == length)),
+        "even" => Some(Value::Bool(index.is_multiple_of(2))),
+        "odd" => Some(Value::Bool(!index.is_multiple_of(2))),
+        "length" => Some(Value::from(length)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_scope_lookup() {
+        let state = json!({ "title": "Lists", "item": "root item", "lists": [{ "name": "a", "items": [1, 2] }] });
+        let list = &state["lists"][0];
+        let frames = [
+            ScopeFrame::Root(&state),
+            ScopeFrame::Item { item: list, index: 0, length: 1 },
+            ScopeFrame::Item { item: &list["items"][1], index: 1, length: 2 },
+        ];
+        let scope = Scope::new(&frames);
+
+        let test_cases = vec![
+            ("item", Some(json!(2))),
+            ("index", Some(json!(1))),
+            ("first", Some(json!(false))),
+            ("last", Some(json!(true))),
+            ("even", Some(json!(false))),
+            ("odd", Some(json!(true))),
+            ("length", Some(json!(2))),
+            ("$index", Some(json!(1))),
+            ("$length", Some(json!(2))),
+            ("$parent.first", Some(json!(true))),
+            ("$parent.length", Some(json!(1))),
+            ("$parent.item.name", Some(json!("a"))),
+            ("$parent.index", Some(json!(0))),
+            ("$parent.items.length", Some(json!(2))),
+            ("name", Some(json!("a"))),
+            ("title", Some(json!("Lists"))),
+            ("$parent.$parent.item", Some(json!("root item"))),
+            ("$parent.$parent.$parent.title", None),
+            ("missing", None),
+        ];
+
+        for (path, expected) in test_cases {
+            let path = Path::parse(path).unwrap();
+            assert_eq!(scope.lookup(&path).map(Cow::into_owned), expected, "Failed on path: {}", path);
+        }
+    }
+
+    #[test]
+    fn test_scope_item_fields_shadow_loop_keys() {
+        let state = json!({ "people": [{ "first": "Ada", "length": 9 }, "Grace"] });
+        let frames = [ScopeFrame::Root(&state), ScopeFrame::Item { item: &state["people"][0], index: 0, length: 2 }];
+        let scope = Scope::new(&frames);
+
+        let test_cases = vec![
+            ("first", json!("Ada")),
+            ("length", json!(9)),
+            ("$first", json!(true)),
+            ("$length", json!(2)),
+            ("last", json!(false)),
+        ];
+        for (path, expected) in test_cases {
+            let path = Path::parse(path).unwrap();
+            assert_eq!(scope.lookup(&path).map(Cow::into_owned), Some(expected), "Failed on path: {}", path);
+        }
+
+        // Items without fields keep the position, even where the item has a length of its own.
+        let frames = [ScopeFrame::Root(&state), ScopeFrame::Item { item: &state["people"][1], index: 1, length: 2 }];
+        let scope = Scope::new(&frames);
+        assert_eq!(scope.lookup(&Path::parse("length").unwrap()).map(Cow::into_owned), Some(json!(2)));
+        assert_eq!(scope.lookup(&Path::parse("item.length").unwrap()).map(Cow::into_owned), Some(json!(5)));
+    }
+}
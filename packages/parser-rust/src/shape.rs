@@ -0,0 +1,336 @@
+use crate::expression::*;
+use crate::protocol::*;
+use crate::scope::*;
+use crate::values::*;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::fmt;
+
+// What a protocol expects of the state value at a path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shape {
+    // Written as text or an attribute value: a string, number, boolean or null.
+    Scalar,
+    // Repeated over.
+    Array,
+    // Read by an expression or a filtered signal, where any value can be used.
+    Any,
+}
+
+// A path the protocol reads, `items` are the bindings of the repeat template checked against every item
+// of an array. Paths within items are resolved like when rendering, so they can use `item`, `$parent`
+// and the enclosing scopes. `optional` paths are only read by signals and attributes with a default
+// value, so they are not reported when missing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateBinding {
+    pub path: Path,
+    pub shape: Shape,
+    pub optional: bool,
+    pub items: Vec<StateBinding>,
+}
+
+// The state paths a protocol reads and their expected shapes. Invalid paths and expressions are left
+// out, `validate` reports them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StateShape {
+    pub bindings: Vec<StateBinding>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StateIssueKind {
+    Missing,
+    // An array or object where a scalar was expected.
+    ExpectedScalar,
+    // A value that is not an array where a repeat expected one.
+    ExpectedArray,
+}
+
+// A state value that does not match the shape, `items` are the repeats and item indices it was found in
+// from the outermost.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateIssue {
+    pub path: String,
+    pub items: Vec<(String, usize)>,
+    pub kind: StateIssueKind,
+}
+
+impl fmt::Display for StateIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            StateIssueKind::Missing => write!(f, "Missing state '{}'", self.path)?,
+            StateIssueKind::ExpectedScalar => write!(f, "Expected a scalar for state '{}'", self.path)?,
+            StateIssueKind::ExpectedArray => write!(f, "Expected an array for state '{}'", self.path)?,
+        }
+        for (path, index) in self.items.iter().rev() {
+            write!(f, " in item {} of '{}'", index, path)?;
+        }
+        Ok(())
+    }
+}
+
+impl StateShape {
+    pub fn from_protocol(protocol: &BuildTimeRenderingProtocol) -> StateShape {
+        let mut shape = StateShape::default();
+        collect_bindings(protocol, &protocol.streams, false, &mut Vec::new(), &mut shape.bindings);
+        shape
+    }
+
+    // Checks a state value against the shape, reporting every missing or mismatched value.
+    pub fn check(&self, state: &Value) -> Vec<StateIssue> {
+        let mut issues = Vec::new();
+        check_bindings(&self.bindings, &mut vec![ScopeFrame::Root(state)], &mut Vec::new(), &mut issues);
+        issues
+    }
+}
+
+// Collects the bindings of a stream list, following repeat templates into their items. `templates` are
+// the templates being collected, so recursive templates are only followed once.
+fn collect_bindings<'a>(
+    protocol: &'a BuildTimeRenderingProtocol,
+    streams: &'a [BuildTimeRenderingStream],
+    in_item: bool,
+    templates: &mut Vec<&'a str>,
+    bindings: &mut Vec<StateBinding>,
+) {
+    for stream in streams {
+        match stream {
+            BuildTimeRenderingStream::Attribute(attribute_stream) => {
+                let shape = if attribute_stream.expression { Shape::Any } else { Shape::Scalar };
+                let (value, optional) = (&attribute_stream.value, attribute_stream.default_value.is_some());
+                collect_binding(value, attribute_stream.expression, shape, optional, in_item, bindings);
+            }
+            BuildTimeRenderingStream::Raw(_) | BuildTimeRenderingStream::WhenEnd => {}
+            BuildTimeRenderingStream::Repeat(repeat_stream) => {
+                if let Ok(path) = Path::parse(&repeat_stream.value) {
+                    let mut items = Vec::new();
+                    let name = repeat_stream.template.as_str();
+                    if let Some(template) = protocol.templates.get(name).filter(|_| !templates.contains(&name)) {
+                        templates.push(name);
+                        collect_bindings(protocol, &template.streams, true, templates, &mut items);
+                        templates.pop();
+                    }
+                    add_binding(bindings, StateBinding { path, shape: Shape::Array, optional: false, items });
+                }
+                collect_bindings(protocol, &repeat_stream.empty, in_item, templates, bindings);
+            }
+            BuildTimeRenderingStream::Signal(signal_stream) => {
+                let shape = if signal_stream.expression || !signal_stream.filters.is_empty() { Shape::Any } else { Shape::Scalar };
+                let (value, optional) = (&signal_stream.value, signal_stream.default_value.is_some());
+                collect_binding(value, signal_stream.expression, shape, optional, in_item, bindings);
+            }
+            BuildTimeRenderingStream::When(when_stream) => {
+                collect_binding(&when_stream.value, true, Shape::Any, false, in_item, bindings);
+                collect_bindings(protocol, &when_stream.streams, in_item, templates, bindings);
+                collect_bindings(protocol, &when_stream.else_streams, in_item, templates, bindings);
+            }
+        }
+    }
+}
+
+fn collect_binding(
+    value: &str,
+    expression: bool,
+    shape: Shape,
+    optional: bool,
+    in_item: bool,
+    bindings: &mut Vec<StateBinding>,
+) {
+    let mut paths = Vec::new();
+    if expression {
+        if let Ok(expr) = parse(value) {
+            collect_paths(&expr, &mut paths);
+        }
+    } else if let Ok(path) = Path::parse(value) {
+        paths.push(path);
+    }
+
+    for path in paths {
+        // The position of an item is always available.
+        let is_loop_key = matches!(
+            path.segments(),
+            [PathSegment::Key(key)] if loop_value(key.strip_prefix('$').unwrap_or(key), 0, 1).is_some()
+        );
+        if !(in_item && is_loop_key) {
+            add_binding(bindings, StateBinding { path, shape, optional, items: Vec::new() });
+        }
+    }
+}
+
+fn collect_paths(expr: &Expr, paths: &mut Vec<Path>) {
+    match expr {
+        Expr::Literal(_) => {}
+        Expr::Path(path) => paths.push(path.clone()),
+        Expr::Unary(_, operand) => collect_paths(operand, paths),
+        Expr::Binary(_, left, right) => {
+            collect_paths(left, paths);
+            collect_paths(right, paths);
+        }
+        Expr::Conditional(condition, then, otherwise) => {
+            collect_paths(condition, paths);
+            collect_paths(then, paths);
+            collect_paths(otherwise, paths);
+        }
+        Expr::Call(_, args) => args.iter().for_each(|arg| collect_paths(arg, paths)),
+    }
+}
+
+// Adds a binding once, merging the items of arrays repeated more than once. A path is optional only when
+// every read of it has a default value.
+fn add_binding(bindings: &mut Vec<StateBinding>, binding: StateBinding) {
+    match bindings.iter_mut().find(|b| b.path == binding.path && b.shape == binding.shape) {
+        Some(existing) => {
+            existing.optional &= binding.optional;
+            binding.items.into_iter().for_each(|item| add_binding(&mut existing.items, item));
+        }
+        None => bindings.push(binding),
+    }
+}
+
+fn check_bindings<'a>(
+    bindings: &[StateBinding],
+    frames: &mut Vec<ScopeFrame<'a>>,
+    items: &mut Vec<(String, usize)>,
+    issues: &mut Vec<StateIssue>,
+) {
+    for binding in bindings {
+        let value = Scope::new(frames).lookup(&binding.path);
+        let kind = match (value, binding.shape) {
+            (None, _) if binding.optional => continue,
+            (None, _) => StateIssueKind::Missing,
+            (Some(value), Shape::Scalar) if value.is_array() || value.is_object() => StateIssueKind::ExpectedScalar,
+            // Each item is checked against the template bindings within its own scope, as the repeat renders it.
+            (Some(Cow::Borrowed(Value::Array(values))), Shape::Array) => {
+                for (index, item) in values.iter().enumerate() {
+                    frames.push(ScopeFrame::Item { item, index, length: values.len() });
+                    items.push((binding.path.to_string(), index));
+                    check_bindings(&binding.items, frames, items, issues);
+                    items.pop();
+                    frames.pop();
+                }
+                continue;
+            }
+            (Some(_), Shape::Array) => StateIssueKind::ExpectedArray,
+            _ => continue,
+        };
+        issues.push(StateIssue {
+            path: binding.path.to_string(),
+            items: items.clone(),
+            kind,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn protocol() -> BuildTimeRenderingProtocol {
+        serde_json::from_value(json!({
+            "streams": [
+                { "type": "signal", "value": "title" },
+                { "type": "attribute", "name": "class", "value": "theme" },
+                { "type": "signal", "value": "total", "filters": ["number:2"] },
+                { "type": "when", "value": "len(todos) > limit && !hidden", "else": [{ "type": "signal", "value": "title" }] },
+                { "type": "repeat", "value": "todos", "template": "x-todo", "empty": [{ "type": "signal", "value": "empty" }] }
+            ],
+            "templates": {
+                "x-todo": {
+                    "streams": [
+                        { "type": "signal", "value": "item.name" },
+                        { "type": "signal", "value": "index" },
+                        { "type": "signal", "value": "$parent.owner" },
+                        { "type": "repeat", "value": "item.children", "template": "x-todo" }
+                    ]
+                }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_state_shape_from_protocol() {
+        let shape = StateShape::from_protocol(&protocol());
+        let summary: Vec<(String, Shape, usize)> = shape
+            .bindings
+            .iter()
+            .map(|binding| (binding.path.to_string(), binding.shape, binding.items.len()))
+            .collect();
+        let expected = [
+            ("title", Shape::Scalar, 0),
+            ("theme", Shape::Scalar, 0),
+            ("total", Shape::Any, 0),
+            ("todos", Shape::Any, 0),
+            ("limit", Shape::Any, 0),
+            ("hidden", Shape::Any, 0),
+            ("todos", Shape::Array, 3),
+            ("empty", Shape::Scalar, 0),
+        ];
+        let expected: Vec<(String, Shape, usize)> =
+            expected.iter().map(|(path, shape, items)| (path.to_string(), *shape, *items)).collect();
+        assert_eq!(summary, expected);
+
+        // The recursive template is followed once.
+        let todo = &shape.bindings[6];
+        let items: Vec<String> = todo.items.iter().map(|binding| binding.path.to_string()).collect();
+        assert_eq!(items, vec!["item.name", "$parent.owner", "item.children"]);
+        assert_eq!(todo.items[2].items, Vec::new());
+    }
+
+    #[test]
+    fn test_state_shape_check() {
+        let shape = StateShape::from_protocol(&protocol());
+        let state = json!({
+            "title": ["not", "scalar"],
+            "total": { "amount": 1 },
+            "limit": 1,
+            "hidden": false,
+            "empty": "None",
+            "owner": "me",
+            "todos": [
+                { "name": "a", "children": [{ "name": "b" }] },
+                { "children": "none" },
+                { "name": "c", "children": [] }
+            ]
+        });
+
+        let issues: Vec<String> = shape.check(&state).iter().map(StateIssue::to_string).collect();
+        assert_eq!(
+            issues,
+            vec![
+                "Expected a scalar for state 'title'",
+                "Missing state 'theme'",
+                "Missing state 'item.name' in item 1 of 'todos'",
+                "Expected an array for state 'item.children' in item 1 of 'todos'",
+            ]
+        );
+
+        let issues = shape.check(&json!({ "todos": 1 }));
+        assert!(issues.iter().any(|issue| issue.path == "todos" && issue.kind == StateIssueKind::ExpectedArray));
+    }
+
+    #[test]
+    fn test_state_shape_default_values() {
+        let protocol: BuildTimeRenderingProtocol = serde_json::from_value(json!({
+            "streams": [
+                { "type": "signal", "value": "title", "defaultValue": "Untitled" },
+                { "type": "attribute", "name": "class", "value": "theme", "defaultValue": "light" },
+                { "type": "attribute", "name": "lang", "value": "lang", "defaultValue": "en" },
+                { "type": "signal", "value": "lang" }
+            ],
+            "templates": {}
+        }))
+        .unwrap();
+        let shape = StateShape::from_protocol(&protocol);
+        let optional: Vec<(String, bool)> =
+            shape.bindings.iter().map(|binding| (binding.path.to_string(), binding.optional)).collect();
+        let expected = [("title", true), ("theme", true), ("lang", false)];
+        let expected: Vec<(String, bool)> =
+            expected.iter().map(|(path, optional)| (path.to_string(), *optional)).collect();
+        assert_eq!(optional, expected);
+
+        let issues = shape.check(&json!({ "theme": { "dark": true } }));
+        let issues: Vec<String> = issues.iter().map(StateIssue::to_string).collect();
+        assert_eq!(issues, vec!["Expected a scalar for state 'theme'", "Missing state 'lang'"]);
+    }
+}
@@ -0,0 +1,244 @@
+use crate::compiled::*;
+use crate::protocol::*;
+use std::collections::HashSet;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    // The protocol renders incorrectly, or does not compile.
+    Error,
+    // The protocol renders, but likely not as intended.
+    Warning,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DiagnosticKind {
+    // An error the protocol fails to compile with.
+    Compile(CompileErrorKind),
+    // A repeat names a template missing from the protocol templates.
+    UnknownTemplate,
+    EmptyAttributeName,
+    // A template no repeat renders.
+    UnusedTemplate,
+}
+
+// A problem found in a protocol. `stream_index` is the position of the stream, or of the stream
+// containing it, in the protocol streams or in the streams of `template`. Problems with a whole template
+// have no stream.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub template: Option<String>,
+    pub stream_index: Option<usize>,
+    pub value: String,
+    pub kind: DiagnosticKind,
+}
+
+impl From<CompileError> for Diagnostic {
+    fn from(error: CompileError) -> Self {
+        let stream_index = (error.kind != CompileErrorKind::TemplateWithStreams).then_some(error.stream_index);
+        Diagnostic {
+            severity: Severity::Error,
+            template: error.template,
+            stream_index,
+            value: error.value,
+            kind: DiagnosticKind::Compile(error.kind),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let location = match (&self.template, self.stream_index) {
+            (Some(template), Some(stream_index)) => format!("stream {} of template '{}'", stream_index, template),
+            (Some(template), None) => format!("template '{}'", template),
+            (None, Some(stream_index)) => format!("stream {}", stream_index),
+            (None, None) => "protocol".to_string(),
+        };
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: ", severity)?;
+        match &self.kind {
+            DiagnosticKind::Compile(kind) => {
+                let error = CompileError {
+                    template: self.template.clone(),
+                    stream_index: self.stream_index.unwrap_or_default(),
+                    value: self.value.clone(),
+                    kind: kind.clone(),
+                };
+                write!(f, "{}", error)
+            }
+            DiagnosticKind::UnknownTemplate => write!(f, "Unknown template '{}' in {}", self.value, location),
+            DiagnosticKind::EmptyAttributeName => write!(f, "Empty attribute name in {}", location),
+            DiagnosticKind::UnusedTemplate => write!(f, "Unused {}", location),
+        }
+    }
+}
+
+// Checks a protocol before it is served, without stopping at the first problem. Protocols with errors
+// fail to compile or render incorrectly, warnings point at likely mistakes. Diagnostics are ordered by
+// template and stream, the protocol streams first.
+pub fn validate(protocol: &BuildTimeRenderingProtocol) -> Vec<Diagnostic> {
+    let mut validator = Validator {
+        protocol,
+        template: None,
+        used_templates: HashSet::new(),
+        diagnostics: compile_errors(protocol).into_iter().map(Diagnostic::from).collect(),
+    };
+    validator.validate_streams(&protocol.streams, None);
+
+    let mut names: Vec<&String> = protocol.templates.keys().collect();
+    names.sort();
+    for name in &names {
+        validator.template = Some(name);
+        validator.validate_streams(&protocol.templates[*name].streams, None);
+    }
+
+    validator.template = None;
+    for name in names {
+        if !validator.used_templates.contains(name.as_str()) {
+            validator.report(Severity::Warning, Some(name), None, "", DiagnosticKind::UnusedTemplate);
+        }
+    }
+
+    let mut diagnostics = validator.diagnostics;
+    diagnostics.sort_by(|a, b| (&a.template, a.stream_index).cmp(&(&b.template, b.stream_index)));
+    diagnostics
+}
+
+struct Validator<'a> {
+    protocol: &'a BuildTimeRenderingProtocol,
+    template: Option<&'a str>,
+    used_templates: HashSet<&'a str>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Validator<'a> {
+    fn report(
+        &mut self,
+        severity: Severity,
+        template: Option<&str>,
+        stream_index: Option<usize>,
+        value: &str,
+        kind: DiagnosticKind,
+    ) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            template: template.map(str::to_string),
+            stream_index,
+            value: value.to_string(),
+            kind,
+        });
+    }
+
+    fn error(&mut self, stream_index: usize, value: &str, kind: DiagnosticKind) {
+        self.report(Severity::Error, self.template, Some(stream_index), value, kind);
+    }
+
+    // Validates what compiling does not check in a list of streams, the streams of a block are reported
+    // at the index of the stream `containing` them.
+    fn validate_streams(&mut self, streams: &'a [BuildTimeRenderingStream], containing: Option<usize>) {
+        for (index, stream) in streams.iter().enumerate() {
+            let stream_index = containing.unwrap_or(index);
+            match stream {
+                BuildTimeRenderingStream::Attribute(attribute_stream) => {
+                    if attribute_stream.name.trim().is_empty() {
+                        self.error(stream_index, &attribute_stream.value, DiagnosticKind::EmptyAttributeName);
+                    }
+                }
+                BuildTimeRenderingStream::Repeat(repeat_stream) => {
+                    if self.protocol.templates.contains_key(&repeat_stream.template) {
+                        self.used_templates.insert(&repeat_stream.template);
+                    } else {
+                        self.error(stream_index, &repeat_stream.template, DiagnosticKind::UnknownTemplate);
+                    }
+                    self.validate_streams(&repeat_stream.empty, Some(stream_index));
+                }
+                BuildTimeRenderingStream::When(when_stream) => {
+                    self.validate_streams(&when_stream.streams, Some(stream_index));
+                    self.validate_streams(&when_stream.else_streams, Some(stream_index));
+                }
+                BuildTimeRenderingStream::Raw(_)
+                | BuildTimeRenderingStream::Signal(_)
+                | BuildTimeRenderingStream::WhenEnd => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_validate() {
+        let protocol: BuildTimeRenderingProtocol = serde_json::from_value(json!({
+            "streams": [
+                { "type": "raw", "value": "<p " },
+                { "type": "attribute", "name": " ", "value": "title" },
+                { "type": "repeat", "value": "items", "template": "x-missing" },
+                { "type": "signal", "value": "a..b" },
+                { "type": "when", "value": "a &&", "else": [{ "type": "signal", "value": "(", "expression": true }] },
+                { "type": "when", "value": "open", "mode": "structural" },
+                { "type": "repeat", "value": "items", "template": "x-item" },
+                { "type": "whenEnd" },
+                { "type": "whenEnd" },
+                { "type": "when", "value": "unclosed", "mode": "structural" }
+            ],
+            "templates": {
+                "x-item": { "template": "<p></p>", "streams": [{ "type": "signal", "value": "item[" }] },
+                "x-unused": { "template": "<p></p>" }
+            }
+        }))
+        .unwrap();
+
+        let diagnostics = validate(&protocol);
+        let summary: Vec<(Severity, Option<&str>, Option<usize>, &str)> = diagnostics
+            .iter()
+            .map(|d| (d.severity, d.template.as_deref(), d.stream_index, d.value.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Severity::Error, None, Some(1), "title"),
+                (Severity::Error, None, Some(2), "x-missing"),
+                (Severity::Error, None, Some(3), "a..b"),
+                (Severity::Error, None, Some(4), "a &&"),
+                (Severity::Error, None, Some(4), "("),
+                (Severity::Error, None, Some(8), ""),
+                (Severity::Error, None, Some(9), "unclosed"),
+                (Severity::Error, Some("x-item"), None, "<p></p>"),
+                (Severity::Error, Some("x-item"), Some(0), "item["),
+                (Severity::Warning, Some("x-unused"), None, ""),
+            ]
+        );
+
+        let messages: Vec<String> = diagnostics.iter().map(Diagnostic::to_string).collect();
+        assert_eq!(messages[0], "error: Empty attribute name in stream 1");
+        assert_eq!(messages[1], "error: Unknown template 'x-missing' in stream 2");
+        assert!(messages[2].starts_with("error: Invalid path 'a..b' in stream 3: "));
+        assert_eq!(messages[5], "error: Unexpected whenEnd in stream 8");
+        assert_eq!(messages[6], "error: Structural when 'unclosed' in stream 9 is never closed");
+        assert_eq!(messages[7], "error: Template 'x-item' has both template HTML and streams, the HTML is never rendered");
+        assert_eq!(messages[9], "warning: Unused template 'x-unused'");
+    }
+
+    #[test]
+    fn test_validate_valid_protocol() {
+        let protocol: BuildTimeRenderingProtocol = serde_json::from_value(json!({
+            "streams": [
+                { "type": "attribute", "name": "title", "value": "upper(title)", "expression": true },
+                { "type": "repeat", "value": "items", "template": "x-item", "empty": [{ "type": "raw", "value": "None" }] },
+                { "type": "when", "value": "count > 0", "mode": "structural" },
+                { "type": "signal", "value": "count" },
+                { "type": "whenEnd" }
+            ],
+            "templates": { "x-item": { "template": "<slot></slot>" } }
+        }))
+        .unwrap();
+        assert_eq!(validate(&protocol), Vec::new());
+    }
+}
@@ -1,25 +1,285 @@
 extern crate serde_json;
 
 use serde_json::Value;
+use std::borrow::Cow;
+use std::fmt;
 
-// Finds a value in a JSON object by a dotted path.
-pub fn find_value_by_dotted_path(path: &str, state: &Value) -> Option<Value> {
-    let parts: Vec<&str> = path.split('.').collect();
+// A dotted path to a value in a JSON object, split into its segments once so it can be looked up
+// repeatedly without parsing the path again. Besides dotted keys, paths support array indices such as
+// `items.0` or `items[0]`, negative indices counting from the end such as `items[-1]`, and quoted keys
+// such as `map["key with dots"]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Path {
+    segments: Vec<PathSegment>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PathSegment {
+    Key(String),
+    Index(i64),
+}
+
+// A dotted path that cannot be parsed, `position` is the byte offset of the malformed segment.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PathError {
+    pub message: String,
+    pub position: usize,
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl std::error::Error for PathError {}
+
+impl Path {
+    pub fn parse(path: &str) -> Result<Path, PathError> {
+        let error = |message: String, position: usize| PathError { message, position };
+        let mut segments = Vec::new();
+        let mut chars = path.char_indices().peekable();
+        let mut expect_key = true;
+
+        loop {
+            let position = chars.peek().map_or(path.len(), |&(i, _)| i);
+            if expect_key {
+                // A dotted segment, which is an index when it only has digits.
+                let mut end = position;
+                while let Some(&(i, c)) = chars.peek() {
+                    if c == '.' || c == '[' {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                let key = &path[position..end];
+                if key.trim().is_empty() {
+                    return Err(error(format!("Empty segment in path '{}'", path), position));
+                }
+                segments.push(match key.parse::<u32>() {
+                    Ok(index) if key.bytes().all(|b| b.is_ascii_digit()) => PathSegment::Index(index.into()),
+                    _ => PathSegment::Key(key.to_string()),
+                });
+            }
+
+            match chars.next() {
+                None => break,
+                Some((_, '.')) => expect_key = true,
+                Some((open, '[')) => {
+                    segments.push(parse_bracket(path, open, &mut chars)?);
+                    expect_key = false;
+                }
+                Some((i, c)) => return Err(error(format!("Unexpected '{}' in path '{}'", c, path), i)),
+            }
+        }
+
+        Ok(Path { segments })
+    }
+
+    // Splits the path on every dot without validating it, used where malformed paths should simply
+    // not match anything.
+    pub fn lenient(path: &str) -> Path {
+        Path {
+            segments: path.split('.').map(|key| PathSegment::Key(key.to_string())).collect(),
+        }
+    }
+
+    pub fn segments(&self) -> &[PathSegment] {
+        &self.segments
+    }
+
+    // Finds the value at this path, `length` on an array or string is its number of items or characters.
+    pub fn find(&self, state: &Value) -> Option<Value> {
+        self.lookup(state).map(Cow::into_owned)
+    }
+
+    // Borrows the value at this path from the state without copying it. The `length` of an array or
+    // string is computed, so it is the only owned value that can be returned.
+    pub fn lookup<'a>(&self, state: &'a Value) -> Option<Cow<'a, Value>> {
+        lookup_segments(&self.segments, state)
+    }
+}
+
+// Borrows the value at a path given as segments, see `Path::lookup`.
+pub fn lookup_segments<'a>(segments: &[PathSegment], state: &'a Value) -> Option<Cow<'a, Value>> {
     let mut current_value: &Value = state;
 
-    for part in parts.iter() {
-        match current_value {
-            Value::Object(map) => {
-                current_value = map.get(*part)?;
+    for (i, segment) in segments.iter().enumerate() {
+        let is_last = i + 1 == segments.len();
+        current_value = match (current_value, segment) {
+            (Value::Object(map), PathSegment::Key(key)) => map.get(key)?,
+            (Value::Object(map), PathSegment::Index(index)) => map.get(&index.to_string())?,
+            (Value::Array(arr), PathSegment::Index(index)) => {
+                let index = if *index < 0 { arr.len() as i64 + index } else { *index };
+                arr.get(usize::try_from(index).ok()?)?
             }
-            Value::Array(arr) if *part == "length" => {
-                return Some(Value::Number(serde_json::Number::from(arr.len())));
+            (Value::Array(arr), PathSegment::Key(key)) if key == "length" && is_last => {
+                return Some(Cow::Owned(Value::from(arr.len())));
+            }
+            // Counted in UTF-16 code units like JavaScript, so it agrees with the client.
+            (Value::String(s), PathSegment::Key(key)) if key == "length" && is_last => {
+                return Some(Cow::Owned(Value::from(s.encode_utf16().count())));
             }
             _ => return None,
+        };
+    }
+
+    Some(Cow::Borrowed(current_value))
+}
+
+// Parses the contents of `[...]` after the opening bracket at `open`, either an integer index or a
+// quoted key.
+fn parse_bracket(
+    path: &str,
+    open: usize,
+    chars: &mut std::iter::Peekable<std::str::CharIndices>,
+) -> Result<PathSegment, PathError> {
+    let error = |message: &str, position: usize| PathError {
+        message: format!("{} in path '{}'", message, path),
+        position,
+    };
+    let skip_whitespace = |chars: &mut std::iter::Peekable<std::str::CharIndices>| {
+        while chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+    };
+
+    skip_whitespace(chars);
+    let segment = match chars.peek().copied() {
+        Some((_, quote)) if quote == '"' || quote == '\'' => {
+            chars.next();
+            let mut key = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, escaped)) => key.push(escaped),
+                        None => return Err(error("Unterminated key", open)),
+                    },
+                    Some((_, c)) if c == quote => break,
+                    Some((_, c)) => key.push(c),
+                    None => return Err(error("Unterminated key", open)),
+                }
+            }
+            PathSegment::Key(key)
+        }
+        Some((start, _)) => {
+            let mut end = start;
+            while let Some((i, c)) = chars.next_if(|(_, c)| *c == '-' || c.is_ascii_digit()) {
+                end = i + c.len_utf8();
+            }
+            let index = path[start..end].parse::<i64>().map_err(|_| error("Invalid index", start))?;
+            PathSegment::Index(index)
+        }
+        None => return Err(error("Unterminated '['", open)),
+    };
+    skip_whitespace(chars);
+
+    match chars.next() {
+        Some((_, ']')) => Ok(segment),
+        Some((i, _)) => Err(error("Expected ']'", i)),
+        None => Err(error("Unterminated '['", open)),
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                PathSegment::Key(key) if is_plain_key(key) => {
+                    if i > 0 {
+                        write!(f, ".")?;
+                    }
+                    write!(f, "{}", key)?;
+                }
+                PathSegment::Key(key) => write!(f, "[{}]", Value::String(key.clone()))?,
+                PathSegment::Index(index) => write!(f, "[{}]", index)?,
+            }
         }
+        Ok(())
+    }
+}
+
+// Whether a key can be written as a dotted segment and parses back as the same key.
+fn is_plain_key(key: &str) -> bool {
+    !key.trim().is_empty()
+        && !key.contains(['.', '[', ']'])
+        && !key.bytes().all(|b| b.is_ascii_digit())
+}
+
+// Finds a value in a JSON object by a dotted path.
+pub fn find_value_by_dotted_path(path: &str, state: &Value) -> Option<Value> {
+    Path::parse(path).ok()?.find(state)
+}
+
+// Borrows a value in a JSON object by a dotted path, see `Path::lookup`. Prefer parsing the path once
+// with `Path::parse` when looking it up repeatedly.
+pub fn lookup<'a>(path: &str, state: &'a Value) -> Option<Cow<'a, Value>> {
+    Path::parse(path).ok()?.lookup(state)
+}
+
+// Whether a value is considered true in conditions. Empty strings, arrays and objects are false.
+pub fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64() != Some(0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(a) => !a.is_empty(),
+        Value::Object(o) => !o.is_empty(),
+        _ => false,
+    }
+}
+
+// Converts a value to a number like JavaScript does, values without a numeric meaning are NaN.
+pub fn to_number(value: &Value) -> f64 {
+    match value {
+        Value::Null => 0.0,
+        Value::Bool(b) => f64::from(u8::from(*b)),
+        Value::Number(n) => n.as_f64().unwrap_or(f64::NAN),
+        Value::String(s) if s.trim().is_empty() => 0.0,
+        Value::String(s) => s.trim().parse().unwrap_or(f64::NAN),
+        Value::Array(a) if a.is_empty() => 0.0,
+        Value::Array(a) if a.len() == 1 => to_number(&a[0]),
+        _ => f64::NAN,
     }
+}
 
-    Some(current_value.clone())
+// Converts a value to a string like JavaScript does when concatenating.
+pub fn to_text(value: &Value) -> String {
+    match value {
+        Value::Null => "null".to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(_) => number_to_string(to_number(value)),
+        Value::String(s) => s.clone(),
+        Value::Array(a) => a
+            .iter()
+            .map(|v| if v.is_null() { String::new() } else { to_text(v) })
+            .collect::<Vec<String>>()
+            .join(","),
+        Value::Object(_) => "[object Object]".to_string(),
+    }
+}
+
+fn number_to_string(n: f64) -> String {
+    if n == 0.0 {
+        // Also -0, which JavaScript prints as 0.
+        "0".to_string()
+    } else if n.fract() == 0.0 && n.abs() < 1e21 {
+        // Rust prints integral floats without a fraction or an exponent.
+        format!("{}", n)
+    } else {
+        n.to_string()
+    }
+}
+
+// Converts the result of a calculation into a value. Integral numbers become integers so they render
+// without a fraction, and NaN and infinities, which JSON cannot represent, become null where JavaScript
+// has NaN and Infinity.
+pub fn number_to_value(n: f64) -> Value {
+    // `i64::MAX as f64` rounds up to 2^63, which does not fit an i64.
+    if n.fract() == 0.0 && n.abs() < 9.223_372_036_854_776e18 {
+        Value::Number(serde_json::Number::from(n as i64))
+    } else {
+        serde_json::Number::from_f64(n).map_or(Value::Null, Value::Number)
+    }
 }
 
 #[cfg(test)]
@@ -91,4 +351,133 @@ mod tests {
             "Failed to get length of array."
         );
     }
+
+    #[test]
+    fn test_parse_path() {
+        let path = Path::parse("favorite.categories.music").unwrap();
+        assert_eq!(
+            path.segments(),
+            [
+                PathSegment::Key("favorite".to_string()),
+                PathSegment::Key("categories".to_string()),
+                PathSegment::Key("music".to_string())
+            ]
+        );
+        assert_eq!(path.to_string(), "favorite.categories.music");
+
+        let path = Path::parse("items[0].tags[ -1 ].map['key.with\\'dots'].0").unwrap();
+        assert_eq!(
+            path.segments(),
+            [
+                PathSegment::Key("items".to_string()),
+                PathSegment::Index(0),
+                PathSegment::Key("tags".to_string()),
+                PathSegment::Index(-1),
+                PathSegment::Key("map".to_string()),
+                PathSegment::Key("key.with'dots".to_string()),
+                PathSegment::Index(0)
+            ]
+        );
+        assert_eq!(path.to_string(), "items[0].tags[-1].map[\"key.with'dots\"][0]");
+
+        let test_cases = vec![
+            ("", 0),
+            ("a..b", 2),
+            (".a", 0),
+            ("a.", 2),
+            ("a[", 1),
+            ("a[0", 1),
+            ("a[x]", 2),
+            ("a['x]", 1),
+            ("a[0]b", 4),
+            ("a[0 1]", 4),
+            ("[0]", 0),
+        ];
+        for (path, position) in test_cases {
+            assert_eq!(
+                Path::parse(path).map_err(|error| error.position),
+                Err(position),
+                "Failed on path: {}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_find_value_by_index() {
+        let data = json!({
+            "items": [
+                { "title": "first", "tags": ["a", "b"] },
+                { "title": "last", "tags": [] }
+            ],
+            "map": {
+                "key with.dots": 1,
+                "0": "zero"
+            }
+        });
+
+        let test_cases = vec![
+            ("items.0.title", Some(json!("first"))),
+            ("items[0].title", Some(json!("first"))),
+            ("items[1]['title']", Some(json!("last"))),
+            ("items[-1].title", Some(json!("last"))),
+            ("items[-2].tags[-1]", Some(json!("b"))),
+            ("items[2]", None),
+            ("items[-3]", None),
+            ("items.length", Some(json!(2))),
+            ("items['length']", Some(json!(2))),
+            ("items[0].tags.length", Some(json!(2))),
+            ("items[0].title.length", Some(json!(5))),
+            ("items.length.value", None),
+            ("items.title", None),
+            ("map[\"key with.dots\"]", Some(json!(1))),
+            ("map.0", Some(json!("zero"))),
+            ("map[0]", Some(json!("zero"))),
+        ];
+
+        for (path, expected) in test_cases {
+            assert_eq!(find_value_by_dotted_path(path, &data), expected, "Failed on path: {}", path);
+        }
+    }
+
+    #[test]
+    fn test_lookup_borrows() {
+        let data = json!({ "items": [{ "title": "first" }] });
+
+        let title = lookup("items[0].title", &data).unwrap();
+        assert!(matches!(title, Cow::Borrowed(_)));
+        assert!(std::ptr::eq(title.as_ref(), &data["items"][0]["title"]));
+
+        let length = lookup("items.length", &data).unwrap();
+        assert!(matches!(length, Cow::Owned(_)));
+        assert_eq!(length.as_ref(), &json!(1));
+
+        assert!(lookup("items[1]", &data).is_none());
+    }
+
+    #[test]
+    fn test_number_conversions() {
+        assert_eq!(to_number(&json!(null)), 0.0);
+        assert_eq!(to_number(&json!(true)), 1.0);
+        assert_eq!(to_number(&json!(" 2.5 ")), 2.5);
+        assert_eq!(to_number(&json!("")), 0.0);
+        assert!(to_number(&json!("abc")).is_nan());
+        assert_eq!(to_number(&json!([7])), 7.0);
+        assert!(to_number(&json!([1, 2])).is_nan());
+        assert!(to_number(&json!({})).is_nan());
+
+        assert_eq!(to_text(&json!(3.0)), "3");
+        assert_eq!(to_text(&json!(0.5)), "0.5");
+        assert_eq!(to_text(&json!([1, null, "a"])), "1,,a");
+        assert_eq!(to_text(&json!({ "a": 1 })), "[object Object]");
+
+        assert_eq!(number_to_value(3.0), json!(3));
+        assert_eq!(number_to_value(-0.25), json!(-0.25));
+        assert_eq!(number_to_value(f64::NAN), Value::Null);
+        assert_eq!(number_to_value(f64::INFINITY), Value::Null);
+        assert_eq!(number_to_value(2f64.powi(63)), json!(9.223_372_036_854_776e18));
+        assert_eq!(number_to_value(2f64.powi(62)), json!(4611686018427387904i64));
+        assert_eq!(to_text(&json!(1e20)), "100000000000000000000");
+        assert_eq!(to_text(&json!(-0.0)), "0");
+    }
 }
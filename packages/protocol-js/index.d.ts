@@ -2,12 +2,17 @@ export interface BuildTimeRenderingStreamRepeat {
   type: 'repeat'
   value: string
   template: string
+  html?: boolean
+  empty?: BuildTimeRenderingStream[]
 }
 
-export interface BuildTimeRenderingStreamRaw {
+export interface BuildTimeRenderingStreamAttribute {
   type: 'attribute'
   name: string
   value: string
+  defaultValue?: string
+  html?: boolean
+  expression?: boolean
 }
 
 export interface BuildTimeRenderingStreamRaw {
@@ -19,11 +24,22 @@ export interface BuildTimeRenderingStreamSignal {
   type: 'signal'
   value: string
   defaultValue?: string
+  html?: boolean
+  expression?: boolean
+  filters?: string[]
 }
 
 export interface BuildTimeRenderingStreamWhen {
   type: 'when'
   value: string
+  mode?: 'style' | 'merge' | 'hidden' | 'structural'
+  style?: string
+  streams?: BuildTimeRenderingStream[]
+  else?: BuildTimeRenderingStream[]
+}
+
+export interface BuildTimeRenderingStreamWhenEnd {
+  type: 'whenEnd'
 }
 
 export type BuildTimeRenderingStream =
@@ -32,10 +48,14 @@ export type BuildTimeRenderingStream =
   | BuildTimeRenderingStreamRepeat
   | BuildTimeRenderingStreamSignal
   | BuildTimeRenderingStreamWhen
+  | BuildTimeRenderingStreamWhenEnd
 
 export interface BuildTimeRenderingTemplate {
-  style: string
-  template: string
+  style?: string
+  template?: string
+  streams?: BuildTimeRenderingStream[]
+  shadowRootMode?: 'open' | 'closed' | 'none'
+  delegatesFocus?: boolean
 }
 
 export type BuildTimeRenderingStreamTemplateRecords = Record<string, BuildTimeRenderingTemplate>
@@ -0,0 +1,83 @@
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use btjs_parser::parser::AsyncServerHandler;
+use hyper::body::{Body, Bytes, Frame};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+// Number of chunks that can be buffered before the renderer waits for the client to catch up.
+const CHANNEL_CAPACITY: usize = 32;
+
+// Creates a connected handler and body, every chunk written to the handler is streamed by the body.
+pub fn streaming_channel() -> (ChannelServerHandler, ChannelBody) {
+    let (sender, receiver) = channel(CHANNEL_CAPACITY);
+    (ChannelServerHandler { sender: Some(sender) }, ChannelBody { receiver })
+}
+
+// Sends each written chunk to the response body, writes wait while the channel is full.
+pub struct ChannelServerHandler {
+    sender: Option<Sender<Bytes>>,
+}
+
+impl AsyncServerHandler for ChannelServerHandler {
+    async fn write(&mut self, value: &str) {
+        if value.is_empty() {
+            return;
+        }
+        if let Some(sender) = &self.sender {
+            // The receiver is dropped once the client disconnects, stop sending from then on.
+            if sender.send(Bytes::copy_from_slice(value.as_bytes())).await.is_err() {
+                self.sender = None;
+            }
+        }
+    }
+
+    async fn end(&mut self) {
+        // Dropping the sender closes the channel which ends the response body.
+        self.sender = None;
+    }
+}
+
+// A response body that yields the chunks sent through the channel as they arrive.
+pub struct ChannelBody {
+    receiver: Receiver<Bytes>,
+}
+
+impl Body for ChannelBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        self.receiver
+            .poll_recv(cx)
+            .map(|chunk| chunk.map(|chunk| Ok(Frame::data(chunk))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn test_streaming_channel() {
+        let (mut server_handler, mut body) = streaming_channel();
+        let writer = tokio::spawn(async move {
+            server_handler.write("<head>").await;
+            server_handler.write("").await;
+            server_handler.write("<body>").await;
+            server_handler.end().await;
+        });
+
+        let first = body.frame().await.unwrap().unwrap().into_data().unwrap();
+        assert_eq!(first, Bytes::from("<head>"));
+        let second = body.frame().await.unwrap().unwrap().into_data().unwrap();
+        assert_eq!(second, Bytes::from("<body>"));
+        assert!(body.frame().await.is_none());
+        writer.await.unwrap();
+    }
+}
@@ -1,5 +1,10 @@
-use btjs_parser::parser::{handle_btr, ServerHandler};
-use btjs_parser::protocol::{load_protocol_from_file, BuildTimeRenderingProtocol};
+mod channel;
+mod state;
+
+use btjs_parser::compiled::CompiledProtocol;
+use btjs_parser::parser::handle_compiled_btr_async;
+use btjs_parser::protocol::{load_protocol_from_file, ProtocolError};
+use btjs_parser::shape::StateShape;
 use tokio::fs::read;
 
 use std::collections::HashMap;
@@ -7,33 +12,33 @@ use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 
-use http_body_util::Full;
+use channel::streaming_channel;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
 use hyper::body::{Bytes, Incoming};
-use hyper::header::CONTENT_TYPE;
+use hyper::header::{ACCEPT_LANGUAGE, CACHE_CONTROL, CONTENT_TYPE};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
 use mime_guess::from_path;
-use serde_json::Value;
+use serde_json::json;
+use state::{StateProvider, StateRequest};
 use tokio::net::TcpListener;
 
-struct ResponseServerHandler {
-    response: Vec<u8>,
-}
-
-// TODO: Change to channel implementation
-impl ServerHandler for ResponseServerHandler {
-    fn write(&mut self, value: &str) {
-        self.response.extend_from_slice(value.as_bytes());
-    }
+// Where template styles are served, so repeat items link them instead of inlining them. The styles of
+// each route are under `{STYLESHEETS_PATH}/{method}{path}`, as pages may use the same template names.
+const STYLESHEETS_PATH: &str = "/btjs/styles";
 
-    fn end(&mut self) {}
-}
+type Handler = (Arc<CompiledProtocol>, Arc<StateShape>, Arc<dyn StateProvider>);
+type Handlers = HashMap<String, Handler>;
+// The handler key and template of each linked stylesheet URL.
+type Stylesheets = HashMap<String, (String, String)>;
 
 struct BTRServer {
     addr: SocketAddr,
-    handlers: Arc<Mutex<HashMap<String, (BuildTimeRenderingProtocol, Value)>>>,
+    handlers: Arc<Mutex<Handlers>>,
+    stylesheets: Arc<Mutex<Stylesheets>>,
     app_path: String,
 }
 
@@ -42,25 +47,74 @@ impl BTRServer {
         BTRServer {
             addr,
             handlers: Arc::new(Mutex::new(HashMap::new())),
+            stylesheets: Arc::new(Mutex::new(HashMap::new())),
             app_path,
         }
     }
 
-    fn add_handler(&mut self, method: Method, path: &str, protocol: &str, state: Value) {
+    fn add_handler(
+        &mut self,
+        method: Method,
+        path: &str,
+        protocol_path: &str,
+        state_provider: impl StateProvider + 'static,
+    ) -> Result<(), ProtocolError> {
+        let protocol = load_protocol_from_file(protocol_path)?;
+        let shape = StateShape::from_protocol(&protocol);
+        let protocol = CompiledProtocol::try_from(protocol).map_err(|error| ProtocolError::Validation {
+            path: protocol_path.to_string(),
+            error,
+        })?;
+        self.insert_handler(method, path, protocol, shape, Arc::new(state_provider));
+        Ok(())
+    }
+
+    // Registers a compiled protocol and the stylesheet URLs its items link.
+    fn insert_handler(
+        &mut self,
+        method: Method,
+        path: &str,
+        mut protocol: CompiledProtocol,
+        shape: StateShape,
+        state_provider: Arc<dyn StateProvider>,
+    ) {
         let key = format!("{}:{}", method, path);
-        let protocol = load_protocol_from_file(protocol);
-        if let Ok(protocol) = protocol {
-            self.handlers.lock().unwrap().insert(key, (protocol, state));
-        } else {
-            println!("Error loading protocol: {:?}", protocol.err());
+        protocol.stylesheets = Some(format!("{}/{}{}", STYLESHEETS_PATH, method, path));
+        let mut stylesheets = self.stylesheets.lock().unwrap();
+        for (name, template) in &protocol.templates {
+            if let (Some(_), Some(href)) = (&template.style, protocol.stylesheet_href(name)) {
+                stylesheets.insert(href, (key.clone(), name.clone()));
+            }
+        }
+        self.handlers
+            .lock()
+            .unwrap()
+            .insert(key, (Arc::new(protocol), Arc::new(shape), state_provider));
+    }
+
+    // The style of a template for a `GET` of a stylesheet URL, from the protocol of the route it was
+    // linked by.
+    fn find_stylesheet(
+        handlers: &Mutex<Handlers>,
+        stylesheets: &Mutex<Stylesheets>,
+        method: &Method,
+        path: &str,
+    ) -> Option<String> {
+        if method != Method::GET {
+            return None;
         }
+        let (key, template) = stylesheets.lock().unwrap().get(path).cloned()?;
+        let handlers = handlers.lock().unwrap();
+        let (protocol, _, _) = handlers.get(&key)?;
+        protocol.stylesheet(&template).map(str::to_string)
     }
 
     async fn handle_request(
-        handlers: Arc<Mutex<HashMap<String, (BuildTimeRenderingProtocol, Value)>>>,
+        handlers: Arc<Mutex<Handlers>>,
+        stylesheets: Arc<Mutex<Stylesheets>>,
         req: Request<Incoming>,
         app_path: String,
-    ) -> Result<Response<Full<Bytes>>, Infallible> {
+    ) -> Result<Response<BoxBody<Bytes, Infallible>>, Infallible> {
         let start = std::time::Instant::now();
         let key = format!("{}:{}", req.method(), req.uri().path());
 
@@ -69,22 +123,48 @@ impl BTRServer {
             handlers.get(&key).cloned()
         };
 
-        if let Some((protocol, state)) = handler {
-            let mut server_handler = ResponseServerHandler {
-                response: Vec::new(),
+        if let Some(style) = Self::find_stylesheet(&handlers, &stylesheets, req.method(), req.uri().path()) {
+            return Ok(Response::builder()
+                .header(CONTENT_TYPE, "text/css; charset=utf-8")
+                .header(CACHE_CONTROL, "public, max-age=3600")
+                .body(Full::new(Bytes::from(style)).boxed())
+                .unwrap());
+        }
+
+        if let Some((protocol, shape, state_provider)) = handler {
+            let state_request = StateRequest::new(req.method(), req.uri().path(), req.uri().query(), req.headers());
+            // Providers may block on I/O, so they run on the blocking pool rather than on a runtime worker.
+            let state = match tokio::task::spawn_blocking(move || state_provider.state(&state_request)).await {
+                Ok(state) => state,
+                Err(error) => {
+                    eprintln!("{}: state provider failed: {}", key, error);
+                    return Ok(Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(Full::new(Bytes::from("Internal Server Error")).boxed())
+                        .unwrap());
+                }
             };
-            handle_btr(protocol.clone(), state.clone(), &mut server_handler);
-            let duration = start.elapsed();
-            println!(
-                "{}:{}: {}ms",
-                req.method(),
-                req.uri().path(),
-                duration.as_secs_f64() * 1000.0
-            );
+            let (mut server_handler, body) = streaming_channel();
+            let route = format!("{}:{}", req.method(), req.uri().path());
+
+            // Catches state providers that drift from the protocol while developing.
+            if cfg!(debug_assertions) {
+                for issue in shape.check(&state) {
+                    eprintln!("{}: {}", route, issue);
+                }
+            }
 
-            Ok(Response::new(Full::new(Bytes::from(
-                server_handler.response,
-            ))))
+            // Render in its own task so every chunk is sent to the client as soon as it is written.
+            tokio::spawn(async move {
+                handle_compiled_btr_async(&protocol, &state, &mut server_handler).await;
+                let duration = start.elapsed();
+                println!("{}: {}ms", route, duration.as_secs_f64() * 1000.0);
+            });
+
+            Ok(Response::builder()
+                .header(CONTENT_TYPE, "text/html; charset=utf-8")
+                .body(body.boxed())
+                .unwrap())
         } else {
             let path = format!("{}/{}", app_path, req.uri().path().trim_start_matches('/'));
             let path_clone = path.clone();
@@ -94,12 +174,12 @@ impl BTRServer {
                         from_path(&path_clone).first_or_octet_stream().to_string();
                     Ok(Response::builder()
                         .header(CONTENT_TYPE, mime_type)
-                        .body(Full::new(Bytes::from(data)))
+                        .body(Full::new(Bytes::from(data)).boxed())
                         .unwrap())
                 }
                 Err(_) => Ok(Response::builder()
                     .status(StatusCode::NOT_FOUND)
-                    .body(Full::new(Bytes::from("File not found")))
+                    .body(Full::new(Bytes::from("File not found")).boxed())
                     .unwrap()),
             }
         }
@@ -108,6 +188,7 @@ impl BTRServer {
     async fn start(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let listener = TcpListener::bind(&self.addr).await?;
         let handlers = Arc::clone(&self.handlers);
+        let stylesheets = Arc::clone(&self.stylesheets);
         let app_path = self.app_path.clone();
 
         loop {
@@ -115,21 +196,26 @@ impl BTRServer {
             let io = TokioIo::new(stream);
             let http = http1::Builder::new();
             let handlers_clone = Arc::clone(&handlers);
+            let stylesheets_clone = Arc::clone(&stylesheets);
             let app_path_clone = app_path.clone();
             let serve_conn = http.serve_connection(
                 io,
                 service_fn(move |req| {
-                    Self::handle_request(Arc::clone(&handlers_clone), req, app_path_clone.clone())
+                    Self::handle_request(
+                        Arc::clone(&handlers_clone),
+                        Arc::clone(&stylesheets_clone),
+                        req,
+                        app_path_clone.clone(),
+                    )
                 }),
             );
 
-            let handle = tokio::spawn(async {
+            // Each connection is served on its own task, so a slow client never holds up the next one.
+            tokio::spawn(async {
                 if let Err(err) = serve_conn.await {
                     eprintln!("server connection error: {}", err);
                 }
             });
-
-            let _ = handle.await;
         }
     }
 }
@@ -143,14 +229,60 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     }
     let app_path = &args[1];
 
-    let state = Value::Null;
+    // Exposes the request to the page, e.g. `query.name`, `cookies.theme` or `language`.
+    let state = |request: &StateRequest| {
+        let language = request.headers.get(ACCEPT_LANGUAGE).and_then(|value| value.to_str().ok());
+        json!({
+            "method": request.method.as_str(),
+            "path": request.path,
+            "query": request.query,
+            "cookies": request.cookies,
+            "language": language,
+        })
+    };
     let mut server = BTRServer::new(([127, 0, 0, 1], 3000).into(), app_path.to_string());
     server.add_handler(
         Method::GET,
         "/",
         &format!("{}\\index.streams.json", app_path),
         state,
-    );
+    )?;
     server.start().await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btjs_parser::protocol::BuildTimeRenderingProtocol;
+
+    fn insert_handler(server: &mut BTRServer, path: &str, style: &str) {
+        let protocol: BuildTimeRenderingProtocol = serde_json::from_value(json!({
+            "streams": [{ "type": "repeat", "value": "items", "template": "x-item" }],
+            "templates": { "x-item": { "style": style, "template": "<slot></slot>" } }
+        }))
+        .unwrap();
+        let shape = StateShape::from_protocol(&protocol);
+        let protocol = CompiledProtocol::try_from(protocol).unwrap();
+        let state = |_: &StateRequest| json!({});
+        server.insert_handler(Method::GET, path, protocol, shape, Arc::new(state));
+    }
+
+    #[test]
+    fn test_find_stylesheet() {
+        let mut server = BTRServer::new(([127, 0, 0, 1], 0).into(), String::new());
+        insert_handler(&mut server, "/", "b { color: red; }");
+        insert_handler(&mut server, "/todos", "b { color: blue; }");
+        insert_handler(&mut server, "/done/", "b { color: green; }");
+        let find = |method: Method, path: &str| {
+            BTRServer::find_stylesheet(&server.handlers, &server.stylesheets, &method, path)
+        };
+
+        assert_eq!(find(Method::GET, "/btjs/styles/GET/x-item.css").as_deref(), Some("b { color: red; }"));
+        assert_eq!(find(Method::GET, "/btjs/styles/GET/todos/x-item.css").as_deref(), Some("b { color: blue; }"));
+        assert_eq!(find(Method::GET, "/btjs/styles/GET/done/x-item.css").as_deref(), Some("b { color: green; }"));
+        assert_eq!(find(Method::POST, "/btjs/styles/GET/x-item.css"), None);
+        assert_eq!(find(Method::GET, "/btjs/styles/GET/missing/x-item.css"), None);
+        assert_eq!(find(Method::GET, "/btjs/styles/x-item.css"), None);
+    }
+}
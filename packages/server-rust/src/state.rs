@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+
+use hyper::header::COOKIE;
+use hyper::{HeaderMap, Method};
+use serde_json::Value;
+
+// The parts of an incoming request that a state provider can use to build the state. It owns them so the
+// provider can run on the blocking thread pool.
+pub struct StateRequest {
+    pub method: Method,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub headers: HeaderMap,
+    pub cookies: HashMap<String, String>,
+}
+
+impl StateRequest {
+    pub fn new(method: &Method, path: &str, query: Option<&str>, headers: &HeaderMap) -> Self {
+        StateRequest {
+            method: method.clone(),
+            path: path.to_string(),
+            query: parse_query(query.unwrap_or_default()),
+            headers: headers.clone(),
+            cookies: parse_cookies(headers),
+        }
+    }
+}
+
+// Provides the state that is rendered for a request. Called once per request on the blocking thread
+// pool, so providers can read files or databases synchronously.
+pub trait StateProvider: Send + Sync {
+    fn state(&self, request: &StateRequest) -> Value;
+}
+
+// A fixed value renders the same state for every request.
+impl StateProvider for Value {
+    fn state(&self, _request: &StateRequest) -> Value {
+        self.clone()
+    }
+}
+
+impl<F> StateProvider for F
+where
+    F: Fn(&StateRequest) -> Value + Send + Sync,
+{
+    fn state(&self, request: &StateRequest) -> Value {
+        self(request)
+    }
+}
+
+// Parses a url encoded query string into a map, the last value wins for repeated keys.
+fn parse_query(query: &str) -> HashMap<String, String> {
+    form_urlencoded::parse(query.as_bytes()).into_owned().collect()
+}
+
+// Parses all the `Cookie` headers into a map of cookie names to values.
+fn parse_cookies(headers: &HeaderMap) -> HashMap<String, String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(';'))
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            let value = value.trim().trim_matches('"');
+            Some((name.trim().to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::{HeaderValue, ACCEPT_LANGUAGE};
+    use serde_json::json;
+
+    #[test]
+    fn test_state_request_query_and_cookies() {
+        let mut headers = HeaderMap::new();
+        headers.append(COOKIE, HeaderValue::from_static("session=abc; theme=\"dark\""));
+        headers.append(COOKIE, HeaderValue::from_static("lang=en"));
+        headers.append(ACCEPT_LANGUAGE, HeaderValue::from_static("fr-CA"));
+        let request = StateRequest::new(&Method::POST, "/", Some("name=John%20Doe&page=2"), &headers);
+
+        assert_eq!(request.method, Method::POST);
+        assert_eq!(request.headers.get(ACCEPT_LANGUAGE).and_then(|value| value.to_str().ok()), Some("fr-CA"));
+
+        assert_eq!(request.query.get("name").map(String::as_str), Some("John Doe"));
+        assert_eq!(request.query.get("page").map(String::as_str), Some("2"));
+        assert_eq!(request.cookies.get("session").map(String::as_str), Some("abc"));
+        assert_eq!(request.cookies.get("theme").map(String::as_str), Some("dark"));
+        assert_eq!(request.cookies.get("lang").map(String::as_str), Some("en"));
+    }
+
+    #[test]
+    fn test_state_provider_closure() {
+        let provider = |request: &StateRequest| json!({ "path": request.path });
+        let headers = HeaderMap::new();
+        let request = StateRequest::new(&Method::GET, "/todos", None, &headers);
+        assert_eq!(provider.state(&request), json!({ "path": "/todos" }));
+        assert_eq!(Value::Null.state(&request), Value::Null);
+    }
+}